@@ -0,0 +1,116 @@
+//! Liveness and readiness probes for docker-compose and orchestrators.
+
+use crate::config::Config;
+use crate::crypto::JwtIssuer;
+use crate::image_service::ImageService;
+use crate::mongo::Mongo;
+use crate::schema::{DependencyHealth, HealthReport, Status};
+use crate::store::UserStore;
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Time after which a dependency counts as unreachable.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct HealthApi;
+
+impl HealthApi {
+    /// Answers as long as the process serves requests, dependencies are not checked.
+    pub async fn live() -> HttpResponse {
+        HttpResponse::Ok().json(HealthReport {
+            status: Status::Ok,
+            checks: BTreeMap::new(),
+        })
+    }
+
+    /// Checks the storage, see [`check_storage`], the image service and the jwt keys concurrently.
+    /// Answers 503 unless all of them are fine, the body lists every check with its latency.
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer, image_service, config))]
+    pub async fn ready(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        image_service: Data<ImageService>,
+        config: Data<Config>,
+    ) -> HttpResponse {
+        let (mut checks, image_service, jwt_keys) = futures_util::join!(
+            check_storage(users.get_ref().as_ref(), &mongo, &config),
+            check(image_service.ping()),
+            check(async { jwt_issuer.check_keys() }),
+        );
+        checks.insert("image_service", image_service);
+        checks.insert("jwt_keys", jwt_keys);
+
+        if checks.values().all(|c| c.status == Status::Ok) {
+            return HttpResponse::Ok().json(HealthReport {
+                status: Status::Ok,
+                checks,
+            });
+        }
+        warn!("not ready: {:?}", checks);
+        HttpResponse::ServiceUnavailable().json(HealthReport {
+            status: Status::Failed,
+            checks,
+        })
+    }
+}
+
+/// Checks the user store, and mongo if [`Config::stores_in_mongo`].
+/// The other collections are only needed by some routes and do not make the service unready.
+async fn check_storage(
+    users: &dyn UserStore,
+    mongo: &Mongo,
+    config: &Config,
+) -> BTreeMap<&'static str, DependencyHealth> {
+    let (user_store, mongo) = futures_util::join!(check(users.ping()), async {
+        if config.stores_in_mongo() {
+            Some(check(mongo.ping()).await)
+        } else {
+            None
+        }
+    });
+    let mut checks = BTreeMap::from([("user_store", user_store)]);
+    if let Some(mongo) = mongo {
+        checks.insert("mongo", mongo);
+    }
+    checks
+}
+
+async fn check(probe: impl Future<Output = Result<()>>) -> DependencyHealth {
+    let start = Instant::now();
+    let result = match actix_web::rt::time::timeout(CHECK_TIMEOUT, probe).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    DependencyHealth {
+        status: if result.is_ok() {
+            Status::Ok
+        } else {
+            Status::Failed
+        },
+        latency_ms: start.elapsed().as_millis() as u64,
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[actix_web::test]
+    async fn checks_the_user_store_instead_of_an_unused_mongo() {
+        let config = testing::config();
+        let mongo = testing::mongo(&config).await;
+
+        let checks = check_storage(testing::users().as_ref(), &mongo, &config).await;
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks["user_store"].status, Status::Ok);
+    }
+}
@@ -0,0 +1,368 @@
+//! Failed sign in tracking per account and client ip, shared by all replicas through mongo.
+
+use crate::api::middleware::client_ip;
+use crate::api::IntoHttpError;
+use crate::config::LockoutConfig;
+use crate::metrics;
+use crate::mongo::Mongo;
+use crate::schema::{normalize_email, LoginAttempts};
+use actix_web::error::{Error, Result};
+use actix_web::http::{header, StatusCode};
+use actix_web::{error, HttpRequest, HttpResponse};
+use async_trait::async_trait;
+use mongodb::bson::DateTime;
+use tracing::warn;
+
+pub(crate) fn account_key(email: &str) -> String {
+    format!("account:{}", normalize_email(email))
+}
+
+pub(crate) fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// The keys a sign in attempt is counted under.
+pub(crate) struct LoginKeys {
+    account: String,
+    ip: Option<String>,
+}
+
+impl LoginKeys {
+    pub fn new(email: &str, req: &HttpRequest) -> Self {
+        Self {
+            account: account_key(email),
+            ip: client_ip(req).map(|ip| ip_key(&ip)),
+        }
+    }
+
+    fn all(&self) -> Vec<String> {
+        self.ip
+            .iter()
+            .cloned()
+            .chain(std::iter::once(self.account.clone()))
+            .collect()
+    }
+}
+
+/// Storage of the failed attempts, [`Mongo`] shares them between replicas.
+#[async_trait]
+pub(crate) trait LoginAttemptStore: Send + Sync {
+    async fn get_login_attempts(&self, keys: &[String]) -> anyhow::Result<Vec<LoginAttempts>>;
+
+    /// Counts a failed sign in and returns the updated attempts.
+    async fn record_login_failure(
+        &self,
+        key: &str,
+        now: DateTime,
+        expires_at: DateTime,
+    ) -> anyhow::Result<LoginAttempts>;
+
+    /// Locks the key until `until`, the failures start over afterwards.
+    async fn lock_login(&self, key: &str, until: DateTime) -> anyhow::Result<()>;
+
+    async fn clear_login_attempts(&self, keys: &[String]) -> anyhow::Result<u64>;
+}
+
+#[async_trait]
+impl LoginAttemptStore for Mongo {
+    async fn get_login_attempts(&self, keys: &[String]) -> anyhow::Result<Vec<LoginAttempts>> {
+        Mongo::get_login_attempts(self, keys).await
+    }
+
+    async fn record_login_failure(
+        &self,
+        key: &str,
+        now: DateTime,
+        expires_at: DateTime,
+    ) -> anyhow::Result<LoginAttempts> {
+        Mongo::record_login_failure(self, key, now, expires_at).await
+    }
+
+    async fn lock_login(&self, key: &str, until: DateTime) -> anyhow::Result<()> {
+        Mongo::lock_login(self, key, until).await
+    }
+
+    async fn clear_login_attempts(&self, keys: &[String]) -> anyhow::Result<u64> {
+        Mongo::clear_login_attempts(self, keys).await
+    }
+}
+
+fn too_many_attempts(retry_after: i64) -> Error {
+    error::InternalError::from_response(
+        "too many failed attempts",
+        HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.max(1).to_string()))
+            .body("too many failed attempts"),
+    )
+    .into()
+}
+
+/// Seconds until the next attempt is allowed.
+/// Accounts back off exponentially, ips are only locked at their threshold.
+fn retry_after(attempts: &LoginAttempts, config: &LockoutConfig, now: i64) -> Option<i64> {
+    if let Some(until) = attempts.locked_until {
+        let until = until.timestamp_millis() / 1000;
+        if until > now {
+            return Some(until - now);
+        }
+    }
+    if attempts.key.starts_with("account:") && attempts.failures > 0 {
+        let delay = config
+            .backoff_seconds
+            .saturating_mul(1 << (attempts.failures - 1).min(30))
+            .min(config.lockout_seconds);
+        let next = attempts.last_failure.timestamp_millis() / 1000 + delay;
+        if next > now {
+            return Some(next - now);
+        }
+    }
+    None
+}
+
+/// Rejects the attempt with 429 while the account or the ip is backing off or locked.
+pub(crate) async fn check(
+    store: &dyn LoginAttemptStore,
+    config: &LockoutConfig,
+    keys: &LoginKeys,
+) -> Result<()> {
+    let now = DateTime::now().timestamp_millis() / 1000;
+    let attempts = store
+        .get_login_attempts(&keys.all())
+        .await
+        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+    match attempts
+        .iter()
+        .filter_map(|a| retry_after(a, config, now))
+        .max()
+    {
+        Some(seconds) => Err(too_many_attempts(seconds)),
+        None => Ok(()),
+    }
+}
+
+/// Counts a failed attempt and locks every key that reached its threshold.
+pub(crate) async fn record_failure(
+    store: &dyn LoginAttemptStore,
+    config: &LockoutConfig,
+    keys: &LoginKeys,
+) -> Result<()> {
+    let now = DateTime::now();
+    let expires_at = DateTime::from_millis(now.timestamp_millis() + config.lockout_seconds * 1000);
+    for key in keys.all() {
+        let attempts = store
+            .record_login_failure(&key, now, expires_at)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let (scope, threshold) = if key.starts_with("ip:") {
+            ("ip", config.ip_threshold)
+        } else {
+            ("account", config.threshold)
+        };
+        if attempts.failures >= threshold {
+            metrics::LOCKOUTS.with_label_values(&[scope]).inc();
+            store
+                .lock_login(&key, expires_at)
+                .await
+                .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+            warn!(
+                key = %key,
+                failures = attempts.failures,
+                seconds = config.lockout_seconds,
+                "sign in locked out"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the failures of the account, the ip keeps counting.
+pub(crate) async fn record_success(store: &dyn LoginAttemptStore, keys: &LoginKeys) -> Result<()> {
+    store
+        .clear_login_attempts(std::slice::from_ref(&keys.account))
+        .await
+        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Attempts kept like in mongo, without expiry.
+    #[derive(Default)]
+    struct MemoryAttempts(Mutex<HashMap<String, LoginAttempts>>);
+
+    #[async_trait]
+    impl LoginAttemptStore for MemoryAttempts {
+        async fn get_login_attempts(&self, keys: &[String]) -> anyhow::Result<Vec<LoginAttempts>> {
+            let attempts = self.0.lock().unwrap();
+            Ok(keys
+                .iter()
+                .filter_map(|key| attempts.get(key).cloned())
+                .collect())
+        }
+
+        async fn record_login_failure(
+            &self,
+            key: &str,
+            now: DateTime,
+            expires_at: DateTime,
+        ) -> anyhow::Result<LoginAttempts> {
+            let mut attempts = self.0.lock().unwrap();
+            let entry = attempts.entry(key.to_string()).or_insert(LoginAttempts {
+                key: key.to_string(),
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+                expires_at,
+            });
+            entry.failures += 1;
+            entry.last_failure = now;
+            entry.expires_at = expires_at;
+            Ok(entry.clone())
+        }
+
+        async fn lock_login(&self, key: &str, until: DateTime) -> anyhow::Result<()> {
+            if let Some(entry) = self.0.lock().unwrap().get_mut(key) {
+                entry.failures = 0;
+                entry.locked_until = Some(until);
+                entry.expires_at = until;
+            }
+            Ok(())
+        }
+
+        async fn clear_login_attempts(&self, keys: &[String]) -> anyhow::Result<u64> {
+            let mut attempts = self.0.lock().unwrap();
+            Ok(keys
+                .iter()
+                .filter(|key| attempts.remove(*key).is_some())
+                .count() as u64)
+        }
+    }
+
+    fn config() -> LockoutConfig {
+        LockoutConfig {
+            threshold: 3,
+            ip_threshold: 5,
+            backoff_seconds: 1,
+            lockout_seconds: 900,
+        }
+    }
+
+    fn keys(email: &str) -> LoginKeys {
+        LoginKeys {
+            account: account_key(email),
+            ip: Some(ip_key("203.0.113.7")),
+        }
+    }
+
+    fn attempts(key: &str, failures: u32, last_failure: i64) -> LoginAttempts {
+        LoginAttempts {
+            key: key.to_string(),
+            failures,
+            last_failure: DateTime::from_millis(last_failure * 1000),
+            locked_until: None,
+            expires_at: DateTime::from_millis((last_failure + 900) * 1000),
+        }
+    }
+
+    async fn locked_until(store: &MemoryAttempts, key: &str) -> Option<DateTime> {
+        let attempts = store.get_login_attempts(&[key.to_string()]).await.unwrap();
+        attempts.first().and_then(|a| a.locked_until)
+    }
+
+    #[test]
+    fn account_backoff_doubles_up_to_the_lockout() {
+        let config = config();
+        let now = 1_000_000;
+        let account = account_key("bob@example.com");
+        let delays: Vec<_> = [1, 2, 3, 4, 10, 11, 40]
+            .iter()
+            .map(|failures| retry_after(&attempts(&account, *failures, now), &config, now))
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                Some(1),
+                Some(2),
+                Some(4),
+                Some(8),
+                Some(512),
+                Some(900),
+                Some(900)
+            ]
+        );
+        // the wait counts from the last failure
+        assert_eq!(
+            retry_after(&attempts(&account, 3, now), &config, now + 3),
+            Some(1)
+        );
+        assert_eq!(
+            retry_after(&attempts(&account, 3, now), &config, now + 4),
+            None
+        );
+        assert_eq!(retry_after(&attempts(&account, 0, now), &config, now), None);
+    }
+
+    #[test]
+    fn ips_only_wait_while_locked() {
+        let config = config();
+        let now = 1_000_000;
+        let mut ip = attempts(&ip_key("203.0.113.7"), 4, now);
+        assert_eq!(retry_after(&ip, &config, now), None);
+        ip.locked_until = Some(DateTime::from_millis((now + 60) * 1000));
+        assert_eq!(retry_after(&ip, &config, now), Some(60));
+        assert_eq!(retry_after(&ip, &config, now + 60), None);
+    }
+
+    #[actix_web::test]
+    async fn locks_accounts_and_ips_at_their_thresholds() {
+        let config = config();
+        let store = MemoryAttempts::default();
+        let bob = keys("bob@example.com");
+
+        for _ in 0..2 {
+            record_failure(&store, &config, &bob).await.unwrap();
+        }
+        assert!(locked_until(&store, &bob.account).await.is_none());
+        record_failure(&store, &config, &bob).await.unwrap();
+        assert!(locked_until(&store, &bob.account).await.is_some());
+        let error = check(&store, &config, &bob).await.unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // the ip counts the failures of every account
+        let ip = bob.ip.clone().unwrap();
+        let alice = keys("alice@example.com");
+        record_failure(&store, &config, &alice).await.unwrap();
+        assert!(locked_until(&store, &ip).await.is_none());
+        record_failure(&store, &config, &alice).await.unwrap();
+        assert!(locked_until(&store, &ip).await.is_some());
+        assert!(locked_until(&store, &alice.account).await.is_none());
+        assert!(check(&store, &config, &keys("carol@example.com"))
+            .await
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn success_clears_the_account_but_not_the_ip() {
+        let config = config();
+        let store = MemoryAttempts::default();
+        let bob = keys("Bob@Example.com");
+        record_failure(&store, &config, &bob).await.unwrap();
+        record_failure(&store, &config, &bob).await.unwrap();
+
+        record_success(&store, &bob).await.unwrap();
+        let left = store.get_login_attempts(&bob.all()).await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].key, ip_key("203.0.113.7"));
+        assert_eq!(left[0].failures, 2);
+        assert!(check(&store, &config, &keys("bob@example.com"))
+            .await
+            .is_ok());
+    }
+}
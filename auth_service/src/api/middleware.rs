@@ -109,16 +109,27 @@
 //     }
 // }
 
-use std::sync::Arc;
-use actix_web::dev::ServiceRequest;
-use actix_web::{error, HttpMessage};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{error, HttpMessage, HttpRequest, HttpResponse};
 use actix_web::error::Error;
-use actix_web::http::{StatusCode, header::HeaderMap};
+use actix_web::http::{StatusCode, header, header::HeaderMap};
 use actix_web::web::Data;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
+use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+use crate::config::{Config, Limit};
 use crate::crypto::JwtIssuer;
 use crate::mongo::Mongo;
 use crate::schema::Role;
+use crate::store::UserStore;
 
 pub async fn validate_admin(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, Error> {
     validate(req, credentials, Role::Admin).await
@@ -128,17 +139,69 @@ pub async fn validate_user(req: ServiceRequest, credentials: BearerAuth) -> Resu
     validate(req, credentials, Role::User).await
 }
 
+/// Scope a service client needs to read user infos.
+pub const USERS_READ_SCOPE: &str = "users:read";
+
+/// Accepts users as well as service clients holding [`USERS_READ_SCOPE`].
+pub async fn validate_user_or_client(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, Error> {
+    validate_client(req, credentials, Role::User, USERS_READ_SCOPE).await
+}
+
+/// Validates a machine token for `scope`, tokens of users are validated for `role` instead.
 #[tracing::instrument(level="trace", skip(req, credentials))]
-pub async fn validate(req: ServiceRequest, credentials: BearerAuth, role: Role) -> Result<ServiceRequest, Error> {
+pub async fn validate_client(req: ServiceRequest, credentials: BearerAuth, role: Role, scope: &str) -> Result<ServiceRequest, Error> {
     let jwt_validator = req.app_data::<Data<Arc<JwtIssuer>>>().unwrap();
     let mongo = req.app_data::<Data<Arc<Mongo>>>().unwrap();
 
-    if jwt_validator.validate_level(mongo, credentials.token(), role).await {
-        let claims = jwt_validator.decode(credentials.token()).await.unwrap();
+    let claims = match jwt_validator.decode_client(credentials.token()).await {
+        Ok(claims) => claims,
+        Err(_) => return validate(req, credentials, role).await,
+    };
+
+    // deleting a client revokes its tokens
+    if claims.has_scope(scope) && mongo.get_client(&claims.client_id).await.is_ok() {
         req.extensions_mut().insert(claims);
         Ok(req)
     } else {
-        Err(Error::from(error::InternalError::new("", StatusCode::UNAUTHORIZED)))
+        Err(Error::from(error::InternalError::new("", StatusCode::FORBIDDEN)))
+    }
+}
+
+/// Also accepts access tokens issued to OIDC clients, only for the userinfo endpoint.
+#[tracing::instrument(level="trace", skip(req, credentials))]
+pub async fn validate_oidc_user(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, Error> {
+    let jwt_validator = req.app_data::<Data<Arc<JwtIssuer>>>().unwrap();
+    let users = req.app_data::<Data<Arc<dyn UserStore>>>().unwrap();
+    let mongo = req.app_data::<Data<Arc<Mongo>>>().unwrap();
+
+    match jwt_validator.validate_oidc(users, mongo, credentials.token()).await {
+        Ok((claims, _)) => {
+            req.extensions_mut().insert(claims);
+            Ok(req)
+        }
+        Err(e) => {
+            debug!("rejected token: {}", e);
+            Err(Error::from(error::InternalError::new("", StatusCode::UNAUTHORIZED)))
+        }
+    }
+}
+
+/// Accepts first party tokens of users holding `role`.
+#[tracing::instrument(level="trace", skip(req, credentials))]
+pub async fn validate(req: ServiceRequest, credentials: BearerAuth, role: Role) -> Result<ServiceRequest, Error> {
+    let jwt_validator = req.app_data::<Data<Arc<JwtIssuer>>>().unwrap();
+    let users = req.app_data::<Data<Arc<dyn UserStore>>>().unwrap();
+    let mongo = req.app_data::<Data<Arc<Mongo>>>().unwrap();
+
+    match jwt_validator.validate_level(users, mongo, credentials.token(), role).await {
+        Ok((claims, _)) => {
+            req.extensions_mut().insert(claims);
+            Ok(req)
+        }
+        Err(e) => {
+            debug!("rejected token: {}", e);
+            Err(Error::from(error::InternalError::new("", StatusCode::UNAUTHORIZED)))
+        }
     }
 }
 
@@ -148,6 +211,316 @@ pub fn get_jwt(headers: &HeaderMap) -> Option<&str> {
         .get("Authorization")?
         .to_str()
         .ok()
-        .filter(|header| header[..7].contains("Bearer"))
-        .map(|header| &header[7..])
+        .and_then(|header| header.strip_prefix("Bearer "))
+}
+
+/// Address of the client. `X-Forwarded-For` is only believed if the peer is one of
+/// `server.trusted_proxies`, otherwise anyone could pick the address per ip limits count against.
+/// Proxies append to the header, so it is read from the right up to the first untrusted address.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let trusted = match req.app_data::<Data<Config>>() {
+        Some(config) => config.server.trusted_proxies.as_slice(),
+        None => &[],
+    };
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|range| range.contains(ip));
+    if !is_trusted(peer) {
+        return Some(peer.to_string());
+    }
+
+    let mut client = peer;
+    let forwarded = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for address in forwarded.iter().rev() {
+        match address.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    Some(client.to_string())
+}
+
+/// Result of taking a token from a bucket.
+#[derive(Debug, PartialEq)]
+pub enum RateDecision {
+    Allowed,
+    Limited { retry_after: u64 },
+}
+
+/// Storage of the token buckets used by [`RateLimit`].
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    async fn take(&self, key: &str, limit: &Limit) -> anyhow::Result<RateDecision>;
+}
+
+fn now_seconds() -> f64 {
+    OffsetDateTime::now_utc().unix_timestamp_nanos() as f64 / 1e9
+}
+
+fn retry_after(tokens: f64, limit: &Limit) -> u64 {
+    ((1.0 - tokens) / limit.rate()).ceil().max(1.0) as u64
+}
+
+/// Buckets of a single replica.
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    // key -> (tokens, last update)
+    buckets: Mutex<HashMap<String, (f64, f64)>>,
+}
+
+/// Number of buckets after which full ones are dropped.
+const MEMORY_BUCKET_LIMIT: usize = 10_000;
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(&self, key: &str, limit: &Limit) -> anyhow::Result<RateDecision> {
+        let now = now_seconds();
+        let capacity = limit.capacity as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MEMORY_BUCKET_LIMIT {
+            buckets.retain(|_, (tokens, updated)| *tokens + (now - *updated) * limit.rate() < capacity);
+        }
+
+        let (tokens, updated) = buckets.entry(key.to_string()).or_insert((capacity, now));
+        *tokens = (*tokens + (now - *updated) * limit.rate()).min(capacity);
+        *updated = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(RateDecision::Allowed)
+        } else {
+            Ok(RateDecision::Limited { retry_after: retry_after(*tokens, limit) })
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for Mongo {
+    async fn take(&self, key: &str, limit: &Limit) -> anyhow::Result<RateDecision> {
+        let bucket = self
+            .take_rate_limit_token(key, limit.capacity as f64, limit.rate(), now_seconds())
+            .await?;
+        if bucket.allowed {
+            Ok(RateDecision::Allowed)
+        } else {
+            Ok(RateDecision::Limited { retry_after: retry_after(bucket.tokens, limit) })
+        }
+    }
+}
+
+/// Who a bucket belongs to.
+#[derive(Clone, Copy, Debug)]
+pub enum RateKey {
+    Ip,
+    /// The user or service client of the bearer token, the ip for requests without one.
+    User,
+}
+
+/// Token bucket rate limiting, answers 429 with `Retry-After` once the bucket is empty.
+#[derive(Clone)]
+pub struct RateLimit {
+    name: &'static str,
+    limit: Limit,
+    key: RateKey,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimit {
+    pub fn new(name: &'static str, limit: Limit, key: RateKey, store: Arc<dyn RateLimitStore>) -> Self {
+        Self { name, limit, key, store }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            config: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    config: RateLimit,
+}
+
+/// Key of the bucket, tokens are only decoded here, they are validated by the route.
+async fn rate_key(req: &ServiceRequest, key: RateKey) -> String {
+    if let RateKey::User = key {
+        if let (Some(jwt), Some(jwt_issuer)) = (get_jwt(req.headers()), req.app_data::<Data<Arc<JwtIssuer>>>()) {
+            if let Ok(claims) = jwt_issuer.decode(jwt).await {
+                return format!("user:{}", claims.user_id);
+            }
+            if let Ok(claims) = jwt_issuer.decode_client(jwt).await {
+                return format!("client:{}", claims.client_id);
+            }
+        }
+    }
+    format!("ip:{}", client_ip(req.request()).unwrap_or_default())
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let key = format!("{}:{}", config.name, rate_key(&req, config.key).await);
+            match config.store.take(&key, &config.limit).await {
+                Ok(RateDecision::Limited { retry_after }) => {
+                    debug!("rate limited {}", key);
+                    let response = HttpResponse::TooManyRequests()
+                        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                        .finish();
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+                Ok(RateDecision::Allowed) => {}
+                // a broken store should not take the routes down with it
+                Err(e) => warn!("rate limit store error: {:?}", e),
+            }
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IpRange;
+    use crate::metrics;
+    use crate::schema::UserClaims;
+    use crate::testing;
+    use actix_web::{test, web, App};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+
+    #[actix_web::test]
+    async fn oidc_access_tokens_are_rejected_by_first_party_routes() {
+        let config = testing::config();
+        let jwt_issuer = testing::jwt_issuer(&config).await;
+        let mongo = testing::mongo(&config).await;
+        let users = testing::users();
+        let user = testing::user("user@board.test", "password");
+        users.create_user(&user).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(jwt_issuer.clone()))
+                .app_data(Data::new(mongo))
+                .app_data(Data::new(users))
+                .service(
+                    web::scope("/auth/user")
+                        .wrap(HttpAuthentication::bearer(validate_user))
+                        .route("/update", web::post().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        let mut claims = UserClaims::from(user);
+        claims.scope = Some("openid profile".to_string());
+        claims.aud = Some("client".to_string());
+        let request = test::TestRequest::post()
+            .uri("/auth/user/update")
+            .insert_header(("Authorization", format!("Bearer {}", jwt_issuer.sign(&claims).unwrap())))
+            .to_request();
+
+        let rejected = || {
+            metrics::TOKEN_VALIDATION_FAILURES
+                .with_label_values(&["oidc_client"])
+                .get()
+        };
+        let rejected_before = rejected();
+        let status = match test::try_call_service(&app, request).await {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // rejected for the audience, before the user or the revocation list are looked at
+        assert!(rejected() > rejected_before);
+    }
+
+    fn client_ip_of(peer: &str, forwarded: Option<&str>, trusted: &[&str]) -> Option<String> {
+        let mut config = testing::config();
+        config.server.trusted_proxies = trusted.iter().map(|s| s.parse().unwrap()).collect();
+        let mut request = test::TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .app_data(Data::new(config));
+        if let Some(forwarded) = forwarded {
+            request = request.insert_header(("X-Forwarded-For", forwarded));
+        }
+        client_ip(&request.to_http_request())
+    }
+
+    #[actix_web::test]
+    async fn forwarded_addresses_are_ignored_without_trusted_proxies() {
+        assert_eq!(
+            client_ip_of("203.0.113.7:4000", Some("198.51.100.1"), &[]).as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip_of("203.0.113.7:4000", Some("198.51.100.1"), &["172.16.0.0/12"]).as_deref(),
+            Some("203.0.113.7")
+        );
+    }
+
+    #[actix_web::test]
+    async fn forwarded_addresses_of_trusted_proxies_are_read_from_the_right() {
+        let trusted = ["172.16.0.0/12", "10.0.0.1"];
+        assert_eq!(
+            client_ip_of("172.18.0.5:4000", Some("198.51.100.1"), &trusted).as_deref(),
+            Some("198.51.100.1")
+        );
+        // the client prepended a made up address, the proxies appended the real one
+        assert_eq!(
+            client_ip_of("172.18.0.5:4000", Some("1.2.3.4, 198.51.100.1, 10.0.0.1"), &trusted)
+                .as_deref(),
+            Some("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip_of("172.18.0.5:4000", None, &trusted).as_deref(),
+            Some("172.18.0.5")
+        );
+        assert_eq!(
+            client_ip_of("[::ffff:172.18.0.5]:4000", Some("198.51.100.1"), &trusted).as_deref(),
+            Some("198.51.100.1")
+        );
+    }
+
+    #[actix_web::test]
+    async fn ip_ranges() {
+        let range: IpRange = "172.16.0.0/12".parse().unwrap();
+        assert!(range.contains("172.31.255.1".parse().unwrap()));
+        assert!(!range.contains("172.32.0.1".parse().unwrap()));
+        let single: IpRange = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.prefix, 128);
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("localhost".parse::<IpRange>().is_err());
+    }
 }
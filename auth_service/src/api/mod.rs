@@ -1,138 +1,843 @@
-use crate::crypto::JwtIssuer;
+use crate::config::Config;
+use crate::crypto::{self, JwtIssuer};
 use crate::image_service::ImageService;
+use crate::mail::Mailer;
+use crate::metrics;
 use crate::mongo::Mongo;
+use crate::password_policy::PasswordPolicy;
 use crate::schema::{
-    LoginRequest, RegisteringUser, Role, TokenResponse, UpdateRequestAdmin, UpdateRequestUser,
-    UserClaims, UserInfo, UserInfoFull,
+    normalize_email, AccountStatus, EmailChange, EmailClaims, EmailRequest, FieldError,
+    ForwardAuthQuery, KeyInfo, LoginRequest, LogoutRequest, MfaChallenge, MfaClaims, PasswordReset,
+    RefreshRequest, RegisteringUser, ResetPasswordRequest, Role, SignInResponse, StepUpRequest,
+    TokenQuery, TokenResponse, UnlockRequest, UpdateRequest, UpdateRequestAdmin, UpdateRequestUser,
+    UserClaims, UserInfo, UserInfoFull, UserWithHash, ValidationErrors, VERIFY_EMAIL,
 };
+use crate::store::{self, UserStore};
 use actix_web::error::{Error, Result};
 use actix_web::http::StatusCode;
 use actix_web::web::{Data, Json, ReqData};
 use actix_web::{error, web, HttpRequest, HttpResponse, Responder};
+use jsonwebtoken::jwk::JwkSet;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::str::FromStr;
 use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
 
+use crate::api::lockout::LoginKeys;
 use crate::api::middleware::get_jwt;
 use tracing::{debug, info, trace, warn};
 
+pub mod health;
+mod lockout;
 pub mod middleware;
+pub mod oidc;
+pub mod two_factor;
+pub mod webauthn;
+
+const VERIFICATION_LIFETIME: Duration = Duration::hours(24);
+/// Minimum time between two verification mails to the same user.
+const VERIFICATION_RESEND_INTERVAL: Duration = Duration::minutes(5);
+const PASSWORD_RESET_LIFETIME: Duration = Duration::hours(1);
+const EMAIL_CHANGE_LIFETIME: Duration = Duration::hours(24);
+/// Time the old address can undo a change of the email address.
+const EMAIL_REVERT_LIFETIME: Duration = Duration::days(7);
+/// Time to enter the second factor after the password.
+const MFA_LOGIN_LIFETIME: Duration = Duration::minutes(5);
+/// Time after a sign in during which sensitive changes need no password.
+const STEP_UP_MAX_AGE: Duration = Duration::minutes(5);
 
 pub async fn version() -> impl Responder {
     trace!("version served");
     option_env!("CARGO_PKG_VERSION")
 }
 
+#[tracing::instrument(level = "trace", skip(jwt_issuer))]
+pub async fn jwks(jwt_issuer: Data<Arc<JwtIssuer>>) -> Json<JwkSet> {
+    trace!("jwks served");
+    Json(jwt_issuer.jwks().clone())
+}
+
 pub struct Auth;
 
 impl Auth {
-    #[tracing::instrument(level = "trace", skip(mongo, jwt_issuer))]
+    /// Signs a user in with email and password.
+    /// Users with two factor authentication get a challenge for [`two_factor::TwoFactorApi::sign_in`] instead of tokens.
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer, config, req))]
     pub(crate) async fn sign_in(
+        users: Data<Arc<dyn UserStore>>,
         mongo: Data<Arc<Mongo>>,
         jwt_issuer: Data<Arc<JwtIssuer>>,
+        config: Data<Config>,
         request: web::Json<LoginRequest>,
-    ) -> Result<Json<TokenResponse>> {
-        if mongo.verify_user(&request).await {
-            let user_hashed = mongo
-                .get_user_from_email(&request.email)
-                .await
-                .http_log_result("error finding user", StatusCode::UNAUTHORIZED)?;
-
-            let jwt = jwt_issuer
-                .issue(user_hashed.clone())
-                .http_log_result("jwt error", StatusCode::INTERNAL_SERVER_ERROR)?;
-            info!("giving out JWT to {}", user_hashed.name);
-            Ok(Json(TokenResponse {
-                token: jwt,
-                user: user_hashed.into(),
-            }))
+        req: HttpRequest,
+    ) -> Result<Json<SignInResponse>> {
+        let keys = LoginKeys::new(&request.email, &req);
+        if let Err(e) = lockout::check(mongo.get_ref().as_ref(), &config.lockout, &keys).await {
+            metrics::SIGN_INS.with_label_values(&["locked_out"]).inc();
+            return Err(e);
+        }
+
+        let user = users
+            .get_user_from_email(&normalize_email(&request.email))
+            .await
+            .http_log_result("error finding user", StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|user| crypto::verify(user.hash.0, &request.password));
+        if let Some(user_hashed) = user {
+            lockout::record_success(mongo.get_ref().as_ref(), &keys).await?;
+            if user_hashed.status == AccountStatus::Pending {
+                metrics::SIGN_INS.with_label_values(&["pending"]).inc();
+                return Err(error::ErrorForbidden("email not verified"));
+            }
+
+            if user_hashed.has_two_factor() {
+                let mfa_token = jwt_issuer
+                    .sign(&MfaClaims::new(&user_hashed, MFA_LOGIN_LIFETIME))
+                    .http_log_result("jwt error", StatusCode::INTERNAL_SERVER_ERROR)?;
+                metrics::SIGN_INS.with_label_values(&["mfa_required"]).inc();
+                info!("asking {} for second factor", user_hashed.name);
+                return Ok(Json(SignInResponse::Mfa(MfaChallenge {
+                    mfa_required: true,
+                    mfa_token,
+                })));
+            }
+
+            let tokens = issue_tokens(&mongo, &jwt_issuer, user_hashed).await?;
+            metrics::SIGN_INS.with_label_values(&["success"]).inc();
+            Ok(Json(SignInResponse::Token(tokens)))
         } else {
+            metrics::SIGN_INS
+                .with_label_values(&["invalid_credentials"])
+                .inc();
+            lockout::record_failure(mongo.get_ref().as_ref(), &config.lockout, &keys).await?;
             Err(error::InternalError::new("", StatusCode::UNAUTHORIZED).into())
         }
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo))]
+    /// Registers a pending user and mails a verification link.
+    /// The user can sign in once the link was opened.
+    #[tracing::instrument(level = "trace", skip(users, jwt_issuer, mailer, config, policy))]
     pub async fn sign_up(
-        mongo: Data<Arc<Mongo>>,
+        users: Data<Arc<dyn UserStore>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        mailer: Data<Mailer>,
+        config: Data<Config>,
+        policy: Data<Arc<PasswordPolicy>>,
         user_request: web::Json<RegisteringUser>,
         image_service: Data<ImageService>,
     ) -> Result<impl Responder> {
         trace!("register");
         let name = user_request.name.clone();
         info!("registering user {}", name);
+        if let Err(e) = check_password(
+            &policy,
+            &user_request.password,
+            &user_request.email,
+            &user_request.name,
+        )
+        .await
+        {
+            metrics::SIGN_UPS
+                .with_label_values(&["weak_password"])
+                .inc();
+            return Err(e);
+        }
         let user = user_request
             .0
             .into_user(&image_service, vec![Role::User])
             .await
             .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
-        mongo
-            .create_user(user.into())
+        let mut user: UserWithHash = user.into();
+        user.status = AccountStatus::Pending;
+        user.verification_sent_at = Some(OffsetDateTime::now_utc().unix_timestamp());
+        let created = conflict_result(users.create_user(&user).await);
+        let result = if created.is_ok() {
+            "created"
+        } else {
+            "rejected"
+        };
+        metrics::SIGN_UPS.with_label_values(&[result]).inc();
+        created?;
+        // the account exists now, a failed mail can be retried with resend
+        let _ = send_verification(&jwt_issuer, &mailer, &config, &user).await;
+        Ok(HttpResponse::Created())
+    }
+
+    #[tracing::instrument(level = "trace", skip(users, jwt_issuer))]
+    pub async fn verify_email(
+        users: Data<Arc<dyn UserStore>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        query: web::Query<TokenQuery>,
+    ) -> Result<impl Responder> {
+        let claims: EmailClaims = jwt_issuer
+            .decode_claims(&query.token)
+            .http_result(StatusCode::BAD_REQUEST)?;
+        if claims.purpose != VERIFY_EMAIL {
+            return Err(error::ErrorBadRequest("invalid token"));
+        }
+        let user = find_user(&users, &claims.user_id).await?;
+        if user.email != claims.email {
+            return Err(error::ErrorBadRequest("invalid token"));
+        }
+        users
+            .activate_user(&user.id)
             .await
             .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
-        Ok(HttpResponse::Created())
+        info!("verified email of {}", user.name);
+        Ok(HttpResponse::Ok())
+    }
+
+    /// Sends the verification link again.
+    /// Always accepted, so the response does not reveal whether the address is registered.
+    #[tracing::instrument(level = "trace", skip(users, jwt_issuer, mailer, config))]
+    pub async fn resend_verification(
+        users: Data<Arc<dyn UserStore>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        mailer: Data<Mailer>,
+        config: Data<Config>,
+        request: web::Json<EmailRequest>,
+    ) -> Result<impl Responder> {
+        if let Ok(user) = users.require_user_by_email(&request.email).await {
+            let claimed = users
+                .claim_verification_mail(
+                    &user.id,
+                    OffsetDateTime::now_utc().unix_timestamp(),
+                    VERIFICATION_RESEND_INTERVAL.whole_seconds(),
+                )
+                .await
+                .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+            if claimed {
+                let _ = send_verification(&jwt_issuer, &mailer, &config, &user).await;
+            } else {
+                debug!("not resending verification to {}", user.name);
+            }
+        }
+        Ok(HttpResponse::Accepted())
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo, jwt_issuer))]
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer))]
     pub async fn reissue(
         jwt_issuer: Data<Arc<JwtIssuer>>,
+        users: Data<Arc<dyn UserStore>>,
         mongo: Data<Arc<Mongo>>,
         req: HttpRequest,
     ) -> Result<Json<TokenResponse>> {
         let old_jwt = get_jwt(req.headers()).http_result(StatusCode::BAD_REQUEST)?;
-        let claims = jwt_issuer
-            .decode(old_jwt)
+        let (old_claims, user) = jwt_issuer
+            .validate(&users, &mongo, old_jwt)
             .await
-            .http_result(StatusCode::BAD_REQUEST)?;
-        let new_jwt = jwt_issuer
-            .issue(claims.clone())
-            .http_result(StatusCode::BAD_REQUEST)?;
+            .http_result(StatusCode::UNAUTHORIZED)?;
 
-        let user = mongo
-            .get_user_from_id(&claims.user_id)
+        // fresh jti and expiry, the old token is revoked in exchange
+        let mut claims = UserClaims::from(user.clone());
+        claims.auth_time = old_claims.auth_time;
+        let new_jwt = jwt_issuer
+            .issue(claims)
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        mongo
+            .revoke_token(&old_claims.jti, old_claims.exp)
             .await
-            .http_result(StatusCode::BAD_REQUEST)?;
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
 
         Ok(Json(TokenResponse {
             token: new_jwt,
+            refresh_token: None,
+            user: user.into(),
+        }))
+    }
+
+    /// Exchanges a refresh token for a new JWT and a rotated refresh token.
+    /// Presenting an already used refresh token revokes its whole family.
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer))]
+    pub async fn refresh(
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        request: web::Json<RefreshRequest>,
+    ) -> Result<Json<TokenResponse>> {
+        let hash = crypto::hash_token(&request.refresh_token);
+        let old = match mongo
+            .claim_refresh_token(&hash)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            Some(old) => old,
+            None => {
+                if let Ok(reused) = mongo.get_refresh_token(&hash).await {
+                    warn!(
+                        "refresh token reuse for user {}, revoking family {}",
+                        reused.user_id, reused.family
+                    );
+                    mongo
+                        .revoke_refresh_family(&reused.family)
+                        .await
+                        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+                }
+                return Err(error::InternalError::new("", StatusCode::UNAUTHORIZED).into());
+            }
+        };
+
+        if old.exp < OffsetDateTime::now_utc().unix_timestamp() {
+            return Err(error::InternalError::new(
+                "refresh token expired",
+                StatusCode::UNAUTHORIZED,
+            )
+            .into());
+        }
+
+        let user = users
+            .require_user(&old.user_id)
+            .await
+            .http_result(StatusCode::UNAUTHORIZED)?;
+        // a refresh is no new sign in
+        let mut claims = UserClaims::from(user.clone());
+        claims.auth_time = old.auth_time;
+        let jwt = jwt_issuer
+            .issue(claims)
+            .http_log_result("jwt error", StatusCode::INTERNAL_SERVER_ERROR)?;
+        let (refresh_token, record) =
+            jwt_issuer.issue_refresh(&user.id, Some(old.family), old.auth_time);
+        mongo
+            .insert_refresh_token(&record)
+            .await
+            .http_log_result("refresh token error", StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        info!("refreshed JWT for {}", user.name);
+        Ok(Json(TokenResponse {
+            token: jwt,
+            refresh_token: Some(refresh_token),
             user: user.into(),
         }))
     }
+
+    /// Traefik ForwardAuth endpoint.
+    /// Answers 200 with the user in `X-User-Id`, `X-User-Roles` and `X-User-Name` (percent encoded),
+    /// 401 for missing or invalid tokens and 403 if the user has none of the required roles.
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer, req))]
+    pub async fn forward(
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        query: web::Query<ForwardAuthQuery>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse> {
+        // CORS preflight requests carry no credentials
+        if req
+            .headers()
+            .get("X-Forwarded-Method")
+            .is_some_and(|m| m == "OPTIONS")
+        {
+            return Ok(HttpResponse::Ok().finish());
+        }
+
+        let jwt = get_jwt(req.headers()).http_result(StatusCode::UNAUTHORIZED)?;
+        let (claims, user) = jwt_issuer
+            .validate(&users, &mongo, jwt)
+            .await
+            .http_result(StatusCode::UNAUTHORIZED)?;
+
+        let user_roles = mongo
+            .effective_roles(&user)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        if let Some(required) = &query.role {
+            let required = required
+                .split(',')
+                .map(Role::from_str)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .http_result(StatusCode::BAD_REQUEST)?;
+            if !required.iter().any(|r| user_roles.contains(r)) {
+                trace!("user {} lacks roles {:?}", user.id, required);
+                return Err(error::InternalError::new("", StatusCode::FORBIDDEN).into());
+            }
+        }
+
+        let roles = user_roles
+            .iter()
+            .map(|r| format!("{:?}", r))
+            .collect::<Vec<_>>()
+            .join(",");
+        Ok(HttpResponse::Ok()
+            .insert_header(("X-User-Id", claims.user_id))
+            .insert_header(("X-User-Roles", roles))
+            .insert_header((
+                "X-User-Name",
+                utf8_percent_encode(&user.name, NON_ALPHANUMERIC).to_string(),
+            ))
+            .finish())
+    }
+}
+
+/// Issues a JWT and a new refresh token family to a signed in user.
+pub(crate) async fn issue_tokens(
+    mongo: &Mongo,
+    jwt_issuer: &JwtIssuer,
+    user: UserWithHash,
+) -> Result<TokenResponse> {
+    let claims = UserClaims::from(user.clone());
+    let auth_time = claims.auth_time;
+    let jwt = jwt_issuer
+        .issue(claims)
+        .http_log_result("jwt error", StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (refresh_token, record) = jwt_issuer.issue_refresh(&user.id, None, auth_time);
+    mongo
+        .insert_refresh_token(&record)
+        .await
+        .http_log_result("refresh token error", StatusCode::INTERNAL_SERVER_ERROR)?;
+    info!("giving out JWT to {}", user.name);
+    Ok(TokenResponse {
+        token: jwt,
+        refresh_token: Some(refresh_token),
+        user: user.into(),
+    })
+}
+
+pub struct PasswordApi;
+
+impl PasswordApi {
+    /// Mails a reset link if the address belongs to a user.
+    /// The response is the same either way, the reset is stored and mailed after responding
+    /// so the response time does not tell whether the address is registered.
+    #[tracing::instrument(level = "trace", skip(users, mongo, mailer, config))]
+    pub async fn forgot(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        mailer: Data<Mailer>,
+        config: Data<Config>,
+        request: web::Json<EmailRequest>,
+    ) -> Result<impl Responder> {
+        let user = match users
+            .get_user_from_email(&normalize_email(&request.email))
+            .await
+            .http_log_result("error finding user", StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            Some(user) => user,
+            None => {
+                debug!("password reset for unknown email");
+                return Ok(HttpResponse::Accepted());
+            }
+        };
+
+        let token = crypto::random_token();
+        let reset = PasswordReset {
+            hash: crypto::hash_token(&token),
+            user_id: user.id.clone(),
+            expires_at: mongodb::bson::DateTime::from_millis(
+                (OffsetDateTime::now_utc() + PASSWORD_RESET_LIFETIME).unix_timestamp() * 1000,
+            ),
+        };
+        let link = format!("{}/reset_password?token={}", config.public_url, token);
+        let mongo = mongo.get_ref().clone();
+        let mailer = mailer.get_ref().clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = mongo.insert_password_reset(&reset).await {
+                warn!("storing password reset of user {} failed: {:?}", user.id, e);
+                return;
+            }
+            let _ = mailer
+                .send_password_reset(&user.email, &user.name, &link)
+                .await;
+        });
+        Ok(HttpResponse::Accepted())
+    }
+
+    #[tracing::instrument(level = "trace", skip(users, mongo, policy))]
+    pub async fn reset(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        policy: Data<Arc<PasswordPolicy>>,
+        request: web::Json<ResetPasswordRequest>,
+    ) -> Result<impl Responder> {
+        let hash = crypto::hash_token(&request.token);
+        // checked before taking the token, so a rejected password can be retried
+        if let Ok(Some(reset)) = mongo.get_password_reset(&hash).await {
+            let user = find_user(&users, &reset.user_id).await?;
+            check_password(&policy, &request.password, &user.email, &user.name).await?;
+        }
+
+        let reset = mongo
+            .take_password_reset(&hash)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+            // expired resets may not have been removed by mongo yet
+            .filter(|r| r.expires_at > mongodb::bson::DateTime::now())
+            .ok_or_else(|| error::ErrorBadRequest("invalid token"))?;
+
+        reset_password(&users, &mongo, &reset.user_id, &request.password)
+            .await
+            .http_log_result("password reset failed", StatusCode::INTERNAL_SERVER_ERROR)?;
+        info!("password of user {} reset", reset.user_id);
+        Ok(HttpResponse::Ok())
+    }
+}
+
+pub struct EmailApi;
+
+impl EmailApi {
+    /// Applies a pending email change and mails a revert link to the old address.
+    #[tracing::instrument(level = "trace", skip(users, mongo, mailer, config))]
+    pub async fn confirm(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        mailer: Data<Mailer>,
+        config: Data<Config>,
+        query: web::Query<TokenQuery>,
+    ) -> Result<impl Responder> {
+        let change = mongo
+            .take_email_change(&crypto::hash_token(&query.token), false)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|c| c.expires_at > mongodb::bson::DateTime::now())
+            .ok_or_else(|| error::ErrorBadRequest("invalid token"))?;
+
+        let changed = conflict_result(
+            users
+                .change_email(&change.user_id, &change.old_email, &change.new_email)
+                .await,
+        )?;
+        if !changed {
+            return Err(error::ErrorBadRequest("invalid token"));
+        }
+        info!("email of user {} changed", change.user_id);
+
+        let token = crypto::random_token();
+        mongo
+            .insert_email_change(&EmailChange {
+                hash: crypto::hash_token(&token),
+                revert: true,
+                expires_at: mongodb::bson::DateTime::from_millis(
+                    (OffsetDateTime::now_utc() + EMAIL_REVERT_LIFETIME).unix_timestamp() * 1000,
+                ),
+                ..change.clone()
+            })
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let user = users
+            .require_user(&change.user_id)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let link = format!("{}/auth/email/revert?token={}", config.public_url, token);
+        let mailer = mailer.get_ref().clone();
+        actix_web::rt::spawn(async move {
+            let _ = mailer
+                .send_email_changed(&change.old_email, &user.name, &change.new_email, &link)
+                .await;
+        });
+        Ok(HttpResponse::Ok())
+    }
+
+    /// Restores the old address from the link mailed to it and ends every session.
+    #[tracing::instrument(level = "trace", skip(users, mongo))]
+    pub async fn revert(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        query: web::Query<TokenQuery>,
+    ) -> Result<impl Responder> {
+        let change = mongo
+            .take_email_change(&crypto::hash_token(&query.token), true)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|c| c.expires_at > mongodb::bson::DateTime::now())
+            .ok_or_else(|| error::ErrorBadRequest("invalid token"))?;
+
+        let reverted = conflict_result(
+            revert_email(
+                &users,
+                &mongo,
+                &change.user_id,
+                &change.new_email,
+                &change.old_email,
+            )
+            .await,
+        )?;
+        if !reverted {
+            return Err(error::ErrorBadRequest("invalid token"));
+        }
+        warn!("email change of user {} reverted", change.user_id);
+        Ok(HttpResponse::Ok())
+    }
+}
+
+/// Mails the confirmation link to `new_email` and stores the pending change.
+/// Nothing is stored if the address is taken or the mail can't be sent.
+async fn start_email_change(
+    users: &Arc<dyn UserStore>,
+    mongo: &Mongo,
+    mailer: &Mailer,
+    config: &Config,
+    user: &UserWithHash,
+    new_email: String,
+) -> Result<()> {
+    if users
+        .get_user_from_email(&normalize_email(&new_email))
+        .await
+        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+        .is_some()
+    {
+        return Err(error::ErrorConflict("email already in use"));
+    }
+    let token = crypto::random_token();
+    let link = format!("{}/auth/email/confirm?token={}", config.public_url, token);
+    mailer
+        .send_email_change(&new_email, &user.name, &link)
+        .await
+        .http_log_result(
+            "sending email change failed",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )?;
+    mongo
+        .insert_email_change(&EmailChange {
+            hash: crypto::hash_token(&token),
+            user_id: user.id.clone(),
+            old_email: user.email.clone(),
+            new_email,
+            revert: false,
+            expires_at: mongodb::bson::DateTime::from_millis(
+                (OffsetDateTime::now_utc() + EMAIL_CHANGE_LIFETIME).unix_timestamp() * 1000,
+            ),
+        })
+        .await
+        .http_result(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Looks a user up by id, a missing user is answered with 400 like a failed lookup.
+async fn find_user(users: &Arc<dyn UserStore>, id: &str) -> Result<UserWithHash> {
+    users
+        .require_user(id)
+        .await
+        .http_result(StatusCode::BAD_REQUEST)
+}
+
+/// Applies an update of the user, a new password ends every session.
+/// Fails with a duplicate key error if the address is taken, see [`store::is_duplicate_key`].
+async fn update_user(
+    users: &Arc<dyn UserStore>,
+    mongo: &Mongo,
+    id: &str,
+    update_request: &UpdateRequest,
+) -> anyhow::Result<()> {
+    let (password, email, image, name, roles) = match update_request {
+        // users change their address with a confirmed email change
+        UpdateRequest::User(update) => {
+            (&update.password, &None, &update.image, &update.name, &None)
+        }
+        UpdateRequest::Admin(update) => (
+            &update.password,
+            &update.email,
+            &update.image,
+            &update.name,
+            &update.roles,
+        ),
+    };
+    // hashed once, the update may be applied more than once
+    let hash = password.as_deref().map(crypto::hash);
+
+    let found = users
+        .update_user_with(id, &|user| {
+            if let Some(hash) = hash {
+                user.hash = hash;
+                user.token_version += 1;
+            }
+            if let Some(email) = email {
+                user.email_normalized = normalize_email(email);
+                user.email = email.clone();
+            }
+            if let Some(image) = image {
+                user.image = Some(image.clone());
+            }
+            if let Some(name) = name {
+                user.name = name.clone();
+            }
+            if let Some(roles) = roles {
+                user.roles = roles.clone();
+            }
+            true
+        })
+        .await?;
+    if !found {
+        return Err(anyhow::anyhow!("User not found"));
+    }
+
+    // sessions have to be started again with the new password
+    if hash.is_some() {
+        mongo.revoke_user_refresh_tokens(id).await?;
+    }
+    Ok(())
+}
+
+/// Sets a new password and ends every session of the user.
+/// The reset link went to the users address, so a pending account is activated as well.
+async fn reset_password(
+    users: &Arc<dyn UserStore>,
+    mongo: &Mongo,
+    id: &str,
+    password: &str,
+) -> anyhow::Result<()> {
+    let hash = crypto::hash(password);
+    let found = users
+        .update_user_with(id, &|user| {
+            user.hash = hash;
+            user.token_version += 1;
+            user.status = AccountStatus::Active;
+            true
+        })
+        .await?;
+    if !found {
+        return Err(anyhow::anyhow!("User not found"));
+    }
+    mongo.delete_password_resets(id).await?;
+    mongo.revoke_user_refresh_tokens(id).await
+}
+
+/// Restores the previous address after an unwanted change.
+/// The account may have been taken over, so every session is ended as well.
+async fn revert_email(
+    users: &Arc<dyn UserStore>,
+    mongo: &Mongo,
+    id: &str,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<bool> {
+    if !users.change_email(id, from, to).await? {
+        return Ok(false);
+    }
+    users.bump_token_version(id).await?;
+    mongo.delete_email_changes(id).await?;
+    mongo.revoke_user_refresh_tokens(id).await?;
+    Ok(true)
+}
+
+/// Maps writes rejected by a unique index, like a taken email address, to 409.
+fn conflict_result<T>(result: anyhow::Result<T>) -> Result<T> {
+    match result {
+        Err(err) if store::is_duplicate_key(&err) => Err(error::ErrorConflict("already in use")),
+        result => result.http_result(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Step up authentication for sensitive changes. Tokens from a recent sign in are accepted,
+/// otherwise the current password has to be given. Failures are answered with 403 and the
+/// codes `reauthentication_required` or `invalid_password` for `current_password`.
+async fn require_step_up(
+    mongo: &Mongo,
+    config: &Config,
+    user: &UserWithHash,
+    claims: &UserClaims,
+    current_password: Option<&str>,
+    req: &HttpRequest,
+) -> Result<()> {
+    let step_up_error = |code: &str, message: &str| -> Error {
+        error::InternalError::from_response(
+            "step up authentication failed",
+            HttpResponse::Forbidden().json(ValidationErrors {
+                errors: vec![FieldError {
+                    field: "current_password".into(),
+                    code: code.into(),
+                    message: message.into(),
+                }],
+            }),
+        )
+        .into()
+    };
+
+    if let Some(password) = current_password {
+        // guesses count against the sign in limits
+        let keys = LoginKeys::new(&user.email, req);
+        lockout::check(mongo, &config.lockout, &keys).await?;
+        if crypto::verify(user.hash.0, password) {
+            return Ok(());
+        }
+        lockout::record_failure(mongo, &config.lockout, &keys).await?;
+        return Err(step_up_error(
+            "invalid_password",
+            "the current password is wrong",
+        ));
+    }
+
+    let fresh_since = (OffsetDateTime::now_utc() - STEP_UP_MAX_AGE).unix_timestamp();
+    if claims.auth_time >= fresh_since {
+        return Ok(());
+    }
+    Err(step_up_error(
+        "reauthentication_required",
+        "enter the current password or sign in again",
+    ))
+}
+
+/// Rejects a password violating the policy with a list of field errors.
+async fn check_password(
+    policy: &PasswordPolicy,
+    password: &str,
+    email: &str,
+    name: &str,
+) -> Result<()> {
+    let errors = policy.check(password, email, name).await;
+    if errors.is_empty() {
+        return Ok(());
+    }
+    debug!("password rejected: {:?}", errors);
+    Err(error::InternalError::from_response(
+        "password rejected",
+        HttpResponse::BadRequest().json(ValidationErrors { errors }),
+    )
+    .into())
+}
+
+async fn send_verification(
+    jwt_issuer: &JwtIssuer,
+    mailer: &Mailer,
+    config: &Config,
+    user: &UserWithHash,
+) -> anyhow::Result<()> {
+    let token = jwt_issuer.sign(&EmailClaims::new(user, VERIFY_EMAIL, VERIFICATION_LIFETIME))?;
+    let link = format!("{}/auth/verify_email?token={}", config.public_url, token);
+    mailer
+        .send_verification(&user.email, &user.name, &link)
+        .await
 }
 
 pub struct AdminApi;
 
 impl AdminApi {
-    #[tracing::instrument(level = "trace", skip(mongo))]
-    pub async fn list_users(mongo: Data<Arc<Mongo>>) -> Result<Json<Vec<UserInfoFull>>> {
+    #[tracing::instrument(level = "trace", skip(users))]
+    pub async fn list_users(users: Data<Arc<dyn UserStore>>) -> Result<Json<Vec<UserInfoFull>>> {
         trace!("list_users");
-        return mongo
+        return users
             .get_all_users()
             .await
             .map(|v| Json(v.into_iter().map(UserInfoFull::from).collect()))
             .http_result(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo))]
+    #[tracing::instrument(level = "trace", skip(users, policy))]
     pub async fn create_user(
-        mongo: Data<Arc<Mongo>>,
+        users: Data<Arc<dyn UserStore>>,
+        policy: Data<Arc<PasswordPolicy>>,
         user: web::Json<RegisteringUser>,
         image_service: Data<ImageService>,
     ) -> Result<impl Responder> {
         info!("creating user {}", &user.0.name);
-        mongo
-            .create_user(
-                user.0
-                    .into_user(&image_service, vec![Role::User])
-                    .await
-                    .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
-                    .into(),
-            )
-            .await
-            .http_result(StatusCode::CONFLICT)?;
+        check_password(&policy, &user.password, &user.email, &user.name).await?;
+        conflict_result(
+            users
+                .create_user(
+                    &user
+                        .0
+                        .into_user(&image_service, vec![Role::User])
+                        .await
+                        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+                        .into(),
+                )
+                .await,
+        )?;
         Ok(HttpResponse::Created())
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo, req))]
+    #[tracing::instrument(level = "trace", skip(users, mongo, policy, req))]
     pub async fn update_user(
+        users: Data<Arc<dyn UserStore>>,
         mongo: Data<Arc<Mongo>>,
+        policy: Data<Arc<PasswordPolicy>>,
         req: HttpRequest,
         update_request: Json<UpdateRequestAdmin>,
     ) -> Result<impl Responder> {
@@ -140,35 +845,101 @@ impl AdminApi {
             .match_info()
             .get("id")
             .http_result(StatusCode::BAD_REQUEST)?;
+        if let Some(password) = &update_request.password {
+            let user = find_user(&users, id).await?;
+            check_password(
+                &policy,
+                password,
+                update_request.email.as_ref().unwrap_or(&user.email),
+                update_request.name.as_ref().unwrap_or(&user.name),
+            )
+            .await?;
+        }
 
         info!("updating user {}", id);
-        mongo
-            .update_user(id, &update_request.into())
-            .await
-            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        conflict_result(update_user(&users, &mongo, id, &update_request.into()).await)?;
         Ok(HttpResponse::Ok())
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo))]
-    pub async fn delete_user(mongo: Data<Arc<Mongo>>, req: HttpRequest) -> Result<impl Responder> {
+    #[tracing::instrument(level = "trace", skip(users))]
+    pub async fn delete_user(
+        users: Data<Arc<dyn UserStore>>,
+        req: HttpRequest,
+    ) -> Result<impl Responder> {
         let id = req
             .match_info()
             .get("id")
             .http_result(StatusCode::BAD_REQUEST)?;
-        mongo
+        users
             .delete_user(id)
             .await
             .http_result(StatusCode::BAD_REQUEST)?;
         Ok(HttpResponse::Ok())
     }
+
+    /// Removes the failed sign ins and lockouts of an account and/or a client ip.
+    #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn unlock(
+        mongo: Data<Arc<Mongo>>,
+        request: web::Json<UnlockRequest>,
+    ) -> Result<impl Responder> {
+        let keys: Vec<String> = request
+            .email
+            .iter()
+            .map(|email| lockout::account_key(email))
+            .chain(request.ip.iter().map(|ip| lockout::ip_key(ip)))
+            .collect();
+        if keys.is_empty() {
+            return Err(error::ErrorBadRequest("email or ip required"));
+        }
+        mongo
+            .clear_login_attempts(&keys)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        info!("unlocked {:?}", keys);
+        Ok(HttpResponse::Ok())
+    }
+}
+
+pub struct KeyApi;
+
+impl KeyApi {
+    #[tracing::instrument(level = "trace", skip(jwt_issuer))]
+    pub async fn list_keys(jwt_issuer: Data<Arc<JwtIssuer>>) -> Json<Vec<KeyInfo>> {
+        Json(jwt_issuer.key_info())
+    }
+
+    #[tracing::instrument(level = "trace", skip(jwt_issuer))]
+    pub async fn promote_key(
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        req: HttpRequest,
+    ) -> Result<Json<Vec<KeyInfo>>> {
+        let kid = req
+            .match_info()
+            .get("kid")
+            .http_result(StatusCode::BAD_REQUEST)?;
+        info!("promoting signing key {}", kid);
+        jwt_issuer
+            .promote(kid)
+            .http_result(StatusCode::BAD_REQUEST)?;
+        Ok(Json(jwt_issuer.key_info()))
+    }
+
+    #[tracing::instrument(level = "trace", skip(jwt_issuer))]
+    pub async fn reload_keys(jwt_issuer: Data<Arc<JwtIssuer>>) -> Result<Json<Vec<KeyInfo>>> {
+        jwt_issuer
+            .reload()
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(Json(jwt_issuer.key_info()))
+    }
 }
 
 pub struct UserApi;
 
 impl UserApi {
-    #[tracing::instrument(level = "trace", skip(mongo, claims))]
+    #[tracing::instrument(level = "trace", skip(users, claims))]
     pub async fn info(
-        mongo: Data<Arc<Mongo>>,
+        users: Data<Arc<dyn UserStore>>,
         claims: ReqData<UserClaims>,
         req: HttpRequest,
     ) -> Result<Json<UserInfo>> {
@@ -176,50 +947,47 @@ impl UserApi {
 
         info!("headers: {:?}", req.headers());
 
-        let user = mongo
-            .get_user_from_id(&claims.user_id)
-            .await
-            .http_result(StatusCode::BAD_REQUEST)?;
+        let user = find_user(&users, &claims.user_id).await?;
 
         Ok(Json(user.into()))
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo))]
-    pub async fn get(mongo: Data<Arc<Mongo>>, req: HttpRequest) -> Result<Json<UserInfo>> {
+    #[tracing::instrument(level = "trace", skip(users))]
+    pub async fn get(users: Data<Arc<dyn UserStore>>, req: HttpRequest) -> Result<Json<UserInfo>> {
         let id = req
             .match_info()
             .get("id")
             .http_result(StatusCode::BAD_REQUEST)?;
-        let user = mongo
-            .get_user_from_id(id)
-            .await
-            .http_result(StatusCode::BAD_REQUEST)?;
+        let user = find_user(&users, id).await?;
         Ok(Json(user.into()))
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo))]
-    pub async fn get_email(mongo: Data<Arc<Mongo>>, req: HttpRequest) -> Result<Json<UserInfo>> {
+    #[tracing::instrument(level = "trace", skip(users))]
+    pub async fn get_email(
+        users: Data<Arc<dyn UserStore>>,
+        req: HttpRequest,
+    ) -> Result<Json<UserInfo>> {
         let email = req
             .match_info()
             .get("email")
             .http_result(StatusCode::BAD_REQUEST)?;
-        let user = mongo
-            .get_user_from_email(email)
+        let user = users
+            .require_user_by_email(email)
             .await
             .http_result(StatusCode::BAD_REQUEST)?;
         Ok(Json(user.into()))
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo))]
+    #[tracing::instrument(level = "trace", skip(users))]
     pub async fn get_batch(
-        mongo: Data<Arc<Mongo>>,
+        users: Data<Arc<dyn UserStore>>,
         batch: web::Json<Vec<String>>,
     ) -> Result<Json<Vec<UserInfo>>> {
         let mut infos = Vec::new();
         for id in batch.0 {
-            let user = mongo.get_user_from_id(&id).await;
-            match user {
-                Ok(user) => infos.push(user.into()),
+            match users.get_user_from_id(&id).await {
+                Ok(Some(user)) => infos.push(user.into()),
+                Ok(None) => warn!("User not found: {}", id),
                 Err(e) => {
                     warn!("{}", e);
                 }
@@ -229,27 +997,138 @@ impl UserApi {
         Ok(Json(infos))
     }
 
-    #[tracing::instrument(level = "trace", skip(mongo))]
+    /// Changing the password or email needs [`require_step_up`].
+    /// A new email is only applied once confirmed, see [`EmailApi::confirm`], and answered with 202.
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(level = "trace", skip(users, mongo, policy, mailer, config, req))]
     pub async fn update(
+        users: Data<Arc<dyn UserStore>>,
         mongo: Data<Arc<Mongo>>,
+        policy: Data<Arc<PasswordPolicy>>,
+        mailer: Data<Mailer>,
+        config: Data<Config>,
         claims: ReqData<UserClaims>,
         update: web::Json<UpdateRequestUser>,
+        req: HttpRequest,
     ) -> Result<impl Responder> {
         info!("updating user {}", claims.user_id);
-        mongo
-            .update_user(&claims.user_id, &update.into())
+        let user = find_user(&users, &claims.user_id).await?;
+        if update.password.is_some() || update.email.is_some() {
+            require_step_up(
+                &mongo,
+                &config,
+                &user,
+                &claims,
+                update.current_password.as_deref(),
+                &req,
+            )
+            .await?;
+        }
+        if let Some(password) = &update.password {
+            check_password(
+                &policy,
+                password,
+                update.email.as_ref().unwrap_or(&user.email),
+                update.name.as_ref().unwrap_or(&user.name),
+            )
+            .await?;
+        }
+        let mut update = update.0;
+        let new_email = update
+            .email
+            .take()
+            .filter(|email| normalize_email(email) != user.email_normalized);
+        // a taken address or a failed mail rejects the whole request before the user is written
+        let status = match new_email {
+            Some(email) => {
+                start_email_change(&users, &mongo, &mailer, &config, &user, email).await?;
+                StatusCode::ACCEPTED
+            }
+            None => StatusCode::OK,
+        };
+        if update.name.is_some() || update.password.is_some() || update.image.is_some() {
+            update_user(
+                &users,
+                &mongo,
+                &claims.user_id,
+                &UpdateRequest::User(update),
+            )
             .await
             .http_result(StatusCode::BAD_REQUEST)?;
-        Ok(HttpResponse::Ok())
+        }
+        Ok(HttpResponse::new(status))
     }
 
+    /// Revokes the presented JWT and, if given, the family of the refresh token.
     #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn logout(
+        mongo: Data<Arc<Mongo>>,
+        claims: ReqData<UserClaims>,
+        request: Option<web::Json<LogoutRequest>>,
+    ) -> Result<impl Responder> {
+        info!("logging out user {}", claims.user_id);
+        mongo
+            .revoke_token(&claims.jti, claims.exp)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        if let Some(refresh_token) = request.and_then(|r| r.0.refresh_token) {
+            if let Ok(token) = mongo
+                .get_refresh_token(&crypto::hash_token(&refresh_token))
+                .await
+            {
+                if token.user_id == claims.user_id {
+                    mongo
+                        .revoke_refresh_family(&token.family)
+                        .await
+                        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+                }
+            }
+        }
+        Ok(HttpResponse::Ok())
+    }
+
+    /// Invalidates every token and refresh token of the user.
+    #[tracing::instrument(level = "trace", skip(users, mongo))]
+    pub async fn logout_all(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        claims: ReqData<UserClaims>,
+    ) -> Result<impl Responder> {
+        info!("logging out user {} everywhere", claims.user_id);
+        users
+            .bump_token_version(&claims.user_id)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        mongo
+            .revoke_user_refresh_tokens(&claims.user_id)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(HttpResponse::Ok())
+    }
+
+    #[tracing::instrument(level = "trace", skip(users, mongo))]
     pub async fn delete(
+        users: Data<Arc<dyn UserStore>>,
         mongo: Data<Arc<Mongo>>,
+        config: Data<Config>,
         claims: ReqData<UserClaims>,
+        step_up: Option<web::Json<StepUpRequest>>,
+        req: HttpRequest,
     ) -> Result<impl Responder> {
         info!("deleting user {}", claims.user_id);
-        mongo
+        let user = find_user(&users, &claims.user_id).await?;
+        let step_up = step_up.map(|s| s.0).unwrap_or_default();
+        require_step_up(
+            &mongo,
+            &config,
+            &user,
+            &claims,
+            step_up.current_password.as_deref(),
+            &req,
+        )
+        .await?;
+        users
             .delete_user(&claims.user_id)
             .await
             .http_result(StatusCode::BAD_REQUEST)?;
@@ -316,3 +1195,215 @@ impl<T> IntoHttpError<T> for anyhow::Result<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::{test, App, HttpMessage};
+
+    /// Sends an update of `user` and returns the status and the stored user afterwards.
+    async fn update(
+        users: Arc<dyn UserStore>,
+        user: &UserWithHash,
+        body: serde_json::Value,
+    ) -> (StatusCode, UserWithHash) {
+        let config = testing::config();
+        let policy = Arc::new(PasswordPolicy::new(config.password_policy.clone()).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(users.clone()))
+                .app_data(Data::new(testing::mongo(&config).await))
+                .app_data(Data::new(policy))
+                .app_data(Data::new(testing::mailer(&config)))
+                .app_data(Data::new(config))
+                .route("/update", web::post().to(UserApi::update)),
+        )
+        .await;
+        let request = test::TestRequest::post()
+            .uri("/update")
+            .set_json(body)
+            .to_request();
+        request
+            .extensions_mut()
+            .insert(UserClaims::from(user.clone()));
+        let status = test::call_service(&app, request).await.status();
+        (status, users.require_user(&user.id).await.unwrap())
+    }
+
+    #[actix_web::test]
+    async fn admins_manage_users_without_mongo() {
+        let config = testing::config();
+        let users = testing::users();
+        let policy = Arc::new(PasswordPolicy::new(config.password_policy.clone()).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(users.clone()))
+                .app_data(Data::new(policy))
+                .app_data(Data::new(ImageService::new(config.image_service.clone())))
+                .route("/users", web::get().to(AdminApi::list_users))
+                .route("/users", web::post().to(AdminApi::create_user))
+                .route("/users/{id}", web::delete().to(AdminApi::delete_user)),
+        )
+        .await;
+        let create = |email: &str| {
+            test::TestRequest::post()
+                .uri("/users")
+                .set_json(serde_json::json!({
+                    "name": "bob",
+                    "email": email,
+                    "password": "correct horse battery staple",
+                }))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, create("Bob@Bücher.example")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = test::call_service(&app, create("bob@xn--bcher-kva.example")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let listed: Vec<UserInfoFull> = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get().uri("/users").to_request(),
+        )
+        .await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].email, "Bob@Bücher.example");
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/users/{}", listed[0].id))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::OK
+        );
+        assert!(users.get_all_users().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn forgot_does_not_wait_for_known_addresses() {
+        let config = testing::config();
+        let users = testing::users();
+        users
+            .create_user(&testing::user("bob@example.com", "password"))
+            .await
+            .unwrap();
+        // storing the reset would block on the unreachable mongo
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(users))
+                .app_data(Data::new(testing::mongo(&config).await))
+                .app_data(Data::new(testing::mailer(&config)))
+                .app_data(Data::new(config))
+                .route("/forgot", web::post().to(PasswordApi::forgot)),
+        )
+        .await;
+
+        for email in ["Bob@Example.com", "alice@example.com"] {
+            let request = test::TestRequest::post()
+                .uri("/forgot")
+                .set_json(serde_json::json!({ "email": email }))
+                .to_request();
+            let response = actix_web::rt::time::timeout(
+                std::time::Duration::from_secs(5),
+                test::call_service(&app, request),
+            )
+            .await
+            .expect("response waited for the reset");
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+    }
+
+    #[actix_web::test]
+    async fn looks_users_up_without_mongo() {
+        let users = testing::users();
+        let user = testing::user("Bob@Example.com", "password");
+        users.create_user(&user).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(users))
+                .route("/info", web::get().to(UserApi::info))
+                .route("/user/{id}", web::get().to(UserApi::get))
+                .route("/email/{email}", web::get().to(UserApi::get_email))
+                .route("/batch", web::post().to(UserApi::get_batch)),
+        )
+        .await;
+        let get = |uri: String| test::TestRequest::get().uri(&uri).to_request();
+
+        let request = get("/info".into());
+        request
+            .extensions_mut()
+            .insert(UserClaims::from(user.clone()));
+        let info: UserInfo = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(info.id, user.id);
+
+        let info: UserInfo =
+            test::call_and_read_body_json(&app, get(format!("/user/{}", user.id))).await;
+        assert_eq!((info.id, info.name), (user.id.clone(), user.name.clone()));
+        let response = test::call_service(&app, get("/user/unknown".into())).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let info: UserInfo =
+            test::call_and_read_body_json(&app, get("/email/bob@EXAMPLE.com".into())).await;
+        assert_eq!(info.id, user.id);
+        let response = test::call_service(&app, get("/email/alice@example.com".into())).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = test::TestRequest::post()
+            .uri("/batch")
+            .set_json(vec![user.id.clone(), "unknown".into()])
+            .to_request();
+        let infos: Vec<UserInfo> = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].id, user.id);
+    }
+
+    #[actix_web::test]
+    async fn a_taken_email_rejects_the_whole_update() {
+        let users = testing::users();
+        let user = testing::user("user@board.test", "password");
+        users.create_user(&user).await.unwrap();
+        users
+            .create_user(&testing::user("other@board.test", "password"))
+            .await
+            .unwrap();
+
+        let (status, stored) = update(
+            users,
+            &user,
+            serde_json::json!({
+                "name": "renamed",
+                "email": "Other@Board.test",
+                "password": "correct horse battery staple",
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(stored.name, user.name);
+        assert_eq!(stored.hash.0, user.hash.0);
+        assert_eq!(stored.token_version, user.token_version);
+    }
+
+    #[actix_web::test]
+    async fn a_failed_confirmation_mail_rejects_the_whole_update() {
+        let users = testing::users();
+        let user = testing::user("user@board.test", "password");
+        users.create_user(&user).await.unwrap();
+
+        // the mail server of the test config is not reachable
+        let (status, stored) = update(
+            users,
+            &user,
+            serde_json::json!({
+                "name": "renamed",
+                "email": "new@board.test",
+                "password": "correct horse battery staple",
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(stored.name, user.name);
+        assert_eq!(stored.hash.0, user.hash.0);
+        assert_eq!(stored.token_version, user.token_version);
+    }
+}
@@ -0,0 +1,540 @@
+use crate::api::IntoHttpError;
+use crate::config::Config;
+use crate::crypto::{self, JwtIssuer};
+use crate::metrics;
+use crate::mongo::Mongo;
+use crate::schema::{
+    AuthorizationCode, AuthorizeRequest, AuthorizeResponse, ClientClaims, ClientCredentials,
+    ClientInfo, IdTokenClaims, IntrospectionRequest, IntrospectionResponse, OidcClient,
+    OidcTokenResponse, ProviderMetadata, RegisteringClient, TokenRequest, UserClaims,
+    UserInfoClaims, GRANT_AUTHORIZATION_CODE, GRANT_CLIENT_CREDENTIALS,
+};
+use crate::store::UserStore;
+use actix_web::error::{Error, Result};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{Data, Json, ReqData};
+use actix_web::{error, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use mongodb::bson::DateTime;
+use std::ops::Add;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tracing::{info, trace, warn};
+use uuid::Uuid;
+
+const CODE_LIFETIME: Duration = Duration::minutes(1);
+const ID_TOKEN_LIFETIME: Duration = Duration::hours(1);
+const CLIENT_TOKEN_LIFETIME: Duration = Duration::minutes(5);
+
+/// Error response of the token endpoint as defined in RFC 6749 section 5.2.
+fn oauth_error(status_code: StatusCode, error: &str) -> Error {
+    warn!("oauth error: {}", error);
+    #[derive(serde::Serialize)]
+    struct ErrorResponse<'a> {
+        error: &'a str,
+    }
+    error::InternalError::from_response(
+        error.to_string(),
+        HttpResponse::build(status_code).json(ErrorResponse { error }),
+    )
+    .into()
+}
+
+/// Appends query parameters to a redirect uri that may already contain a query.
+fn redirect_with(uri: &str, params: &[(&str, &str)]) -> String {
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    format!(
+        "{}{}{}",
+        uri,
+        separator,
+        serde_urlencoded::to_string(params).unwrap()
+    )
+}
+
+fn error_redirect(request: &AuthorizeRequest, error: &str) -> String {
+    let mut params = vec![("error", error)];
+    if let Some(state) = &request.state {
+        params.push(("state", state));
+    }
+    redirect_with(&request.redirect_uri, &params)
+}
+
+/// Finds the client of an authorization request and checks the redirect uri.
+/// Errors here must not be redirected, the redirect uri is not trusted yet.
+async fn find_client(mongo: &Mongo, request: &AuthorizeRequest) -> Result<OidcClient> {
+    let client = mongo
+        .get_client(&request.client_id)
+        .await
+        .http_log_result("unknown client", StatusCode::BAD_REQUEST)?;
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        return Err(error::InternalError::new(
+            "redirect_uri not registered",
+            StatusCode::BAD_REQUEST,
+        )
+        .into());
+    }
+    Ok(client)
+}
+
+/// Checks the remaining parameters, errors are reported back to the client.
+fn check_request(client: &OidcClient, request: &AuthorizeRequest) -> Result<(), &'static str> {
+    if !client
+        .grant_types
+        .iter()
+        .any(|g| g == GRANT_AUTHORIZATION_CODE)
+    {
+        return Err("unauthorized_client");
+    }
+    if request.response_type != "code" {
+        return Err("unsupported_response_type");
+    }
+    if !request.scope.split(' ').any(|s| s == "openid") {
+        return Err("invalid_scope");
+    }
+    match (
+        &request.code_challenge,
+        request.code_challenge_method.as_deref(),
+    ) {
+        (Some(_), Some("S256")) => Ok(()),
+        (Some(_), _) => Err("invalid_request"),
+        // public clients can not keep a secret and have to use PKCE
+        (None, _) if client.secret.is_none() => Err("invalid_request"),
+        (None, _) => Ok(()),
+    }
+}
+
+/// Authenticates a client with HTTP basic auth or the `client_id` and `client_secret` form parameters.
+async fn authenticate_client(
+    mongo: &Mongo,
+    basic: Option<BasicAuth>,
+    client_id: &Option<String>,
+    client_secret: &Option<String>,
+) -> Result<OidcClient> {
+    let (client_id, secret) = match basic {
+        Some(basic) => (
+            basic.user_id().to_string(),
+            basic.password().map(|p| p.to_string()),
+        ),
+        None => (
+            client_id
+                .clone()
+                .ok_or_else(|| oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"))?,
+            client_secret.clone(),
+        ),
+    };
+
+    let client = mongo
+        .get_client(&client_id)
+        .await
+        .map_err(|_| oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"))?;
+    match (&client.secret, secret) {
+        (Some(hash), Some(secret)) if crypto::verify(hash.0, &secret) => Ok(client),
+        (None, None) => Ok(client),
+        _ => Err(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client")),
+    }
+}
+
+pub struct OidcApi;
+
+impl OidcApi {
+    #[tracing::instrument(level = "trace", skip(config, jwt_issuer))]
+    pub async fn discovery(
+        config: Data<Config>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+    ) -> Json<ProviderMetadata> {
+        trace!("discovery served");
+        let issuer = config.oidc.issuer.trim_end_matches('/');
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        Json(ProviderMetadata {
+            issuer: issuer.to_string(),
+            authorization_endpoint: format!("{}/auth/oidc/authorize", issuer),
+            token_endpoint: format!("{}/auth/token", issuer),
+            userinfo_endpoint: format!("{}/auth/oidc/userinfo", issuer),
+            introspection_endpoint: format!("{}/auth/introspect", issuer),
+            jwks_uri: format!("{}/.well-known/jwks.json", issuer),
+            response_types_supported: strings(&["code"]),
+            subject_types_supported: strings(&["public"]),
+            id_token_signing_alg_values_supported: vec![format!("{:?}", jwt_issuer.algorithm())],
+            scopes_supported: strings(&["openid", "profile", "email"]),
+            token_endpoint_auth_methods_supported: strings(&[
+                "client_secret_basic",
+                "client_secret_post",
+                "none",
+            ]),
+            grant_types_supported: strings(&[GRANT_AUTHORIZATION_CODE, GRANT_CLIENT_CREDENTIALS]),
+            code_challenge_methods_supported: strings(&["S256"]),
+            claims_supported: strings(&[
+                "sub",
+                "name",
+                "email",
+                "email_verified",
+                "roles",
+                "nonce",
+            ]),
+        })
+    }
+
+    /// Entry point of the authorization code flow.
+    /// Valid requests are forwarded to the login page of the frontend with all parameters,
+    /// which signs the user in and confirms the request with [`OidcApi::confirm`].
+    #[tracing::instrument(level = "trace", skip(mongo, config, req))]
+    pub async fn authorize(
+        mongo: Data<Arc<Mongo>>,
+        config: Data<Config>,
+        request: web::Query<AuthorizeRequest>,
+        req: HttpRequest,
+    ) -> Result<impl Responder> {
+        let client = find_client(&mongo, &request).await?;
+        let location = match check_request(&client, &request) {
+            Ok(()) => {
+                let separator = if config.oidc.login_url.contains('?') {
+                    '&'
+                } else {
+                    '?'
+                };
+                format!(
+                    "{}{}{}",
+                    config.oidc.login_url,
+                    separator,
+                    req.query_string()
+                )
+            }
+            Err(e) => error_redirect(&request, e),
+        };
+        Ok(HttpResponse::Found()
+            .insert_header((header::LOCATION, location))
+            .finish())
+    }
+
+    /// Issues an authorization code for the signed in user.
+    /// Returns the uri the frontend has to send the user back to.
+    #[tracing::instrument(level = "trace", skip(mongo, claims))]
+    pub async fn confirm(
+        mongo: Data<Arc<Mongo>>,
+        claims: ReqData<UserClaims>,
+        request: Json<AuthorizeRequest>,
+    ) -> Result<Json<AuthorizeResponse>> {
+        let client = find_client(&mongo, &request).await?;
+        if let Err(e) = check_request(&client, &request) {
+            return Ok(Json(AuthorizeResponse {
+                redirect_to: error_redirect(&request, e),
+            }));
+        }
+
+        let code = crypto::random_token();
+        mongo
+            .insert_authorization_code(&AuthorizationCode {
+                hash: crypto::hash_token(&code),
+                client_id: client.client_id.clone(),
+                user_id: claims.user_id.clone(),
+                redirect_uri: request.redirect_uri.clone(),
+                scope: request.scope.clone(),
+                nonce: request.nonce.clone(),
+                code_challenge: request.code_challenge.clone(),
+                auth_time: claims.auth_time,
+                expires_at: DateTime::from_millis(
+                    OffsetDateTime::now_utc()
+                        .add(CODE_LIFETIME)
+                        .unix_timestamp()
+                        * 1000,
+                ),
+            })
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        info!(
+            "user {} authorized client {}",
+            claims.user_id, client.client_id
+        );
+
+        let mut params = vec![("code", code.as_str())];
+        if let Some(state) = &request.state {
+            params.push(("state", state));
+        }
+        Ok(Json(AuthorizeResponse {
+            redirect_to: redirect_with(&request.redirect_uri, &params),
+        }))
+    }
+
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer, config, basic))]
+    pub async fn token(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        config: Data<Config>,
+        basic: Option<BasicAuth>,
+        request: web::Form<TokenRequest>,
+    ) -> Result<HttpResponse> {
+        let client =
+            authenticate_client(&mongo, basic, &request.client_id, &request.client_secret).await?;
+        if !client.grant_types.contains(&request.grant_type) {
+            return Err(oauth_error(StatusCode::BAD_REQUEST, "unauthorized_client"));
+        }
+        let response = match request.grant_type.as_str() {
+            GRANT_AUTHORIZATION_CODE => {
+                Self::authorization_code(&users, &mongo, &jwt_issuer, &config, client, &request)
+                    .await?
+            }
+            GRANT_CLIENT_CREDENTIALS => Self::client_credentials(&jwt_issuer, client, &request)?,
+            _ => {
+                return Err(oauth_error(
+                    StatusCode::BAD_REQUEST,
+                    "unsupported_grant_type",
+                ))
+            }
+        };
+        Ok(HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .json(response))
+    }
+
+    async fn authorization_code(
+        users: &Arc<dyn UserStore>,
+        mongo: &Mongo,
+        jwt_issuer: &JwtIssuer,
+        config: &Config,
+        client: OidcClient,
+        request: &TokenRequest,
+    ) -> Result<OidcTokenResponse> {
+        let invalid_grant = || oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+
+        let code = request
+            .code
+            .as_deref()
+            .ok_or_else(|| oauth_error(StatusCode::BAD_REQUEST, "invalid_request"))?;
+        let record = mongo
+            .take_authorization_code(&crypto::hash_token(code))
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or_else(invalid_grant)?;
+
+        if record.client_id != client.client_id
+            || request.redirect_uri.as_ref() != Some(&record.redirect_uri)
+            || record.expires_at < DateTime::now()
+        {
+            return Err(invalid_grant());
+        }
+        if let Some(challenge) = &record.code_challenge {
+            match &request.code_verifier {
+                Some(verifier) if crypto::pkce_challenge(verifier) == *challenge => (),
+                _ => return Err(invalid_grant()),
+            }
+        }
+
+        let user = users
+            .require_user(&record.user_id)
+            .await
+            .map_err(|_| invalid_grant())?;
+        let roles = mongo
+            .effective_roles(&user)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let mut claims = UserClaims::from(user.clone());
+        claims.scope = Some(record.scope.clone());
+        claims.aud = Some(client.client_id.clone());
+        claims.auth_time = record.auth_time;
+        let access_token = jwt_issuer
+            .sign(&claims)
+            .http_log_result("jwt error", StatusCode::INTERNAL_SERVER_ERROR)?;
+        metrics::TOKENS_ISSUED.with_label_values(&["access"]).inc();
+
+        let now = OffsetDateTime::now_utc();
+        let id_token = jwt_issuer
+            .sign(&IdTokenClaims {
+                iss: config.oidc.issuer.trim_end_matches('/').to_string(),
+                aud: client.client_id.clone(),
+                exp: now.add(ID_TOKEN_LIFETIME).unix_timestamp(),
+                iat: now.unix_timestamp(),
+                nonce: record.nonce,
+                user: UserInfoClaims::new(user.into(), roles, &claims),
+            })
+            .http_log_result("jwt error", StatusCode::INTERNAL_SERVER_ERROR)?;
+        metrics::TOKENS_ISSUED.with_label_values(&["id"]).inc();
+
+        info!("issued tokens for client {}", client.client_id);
+        Ok(OidcTokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: claims.exp - now.unix_timestamp(),
+            scope: record.scope,
+            id_token: Some(id_token),
+        })
+    }
+
+    /// Issues a short lived machine token to a service client.
+    /// Without a `scope` parameter all scopes registered for the client are granted.
+    fn client_credentials(
+        jwt_issuer: &JwtIssuer,
+        client: OidcClient,
+        request: &TokenRequest,
+    ) -> Result<OidcTokenResponse> {
+        if client.secret.is_none() {
+            return Err(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"));
+        }
+        let scope = match &request.scope {
+            Some(scope) => {
+                if !scope
+                    .split(' ')
+                    .all(|s| client.scopes.iter().any(|c| c == s))
+                {
+                    return Err(oauth_error(StatusCode::BAD_REQUEST, "invalid_scope"));
+                }
+                scope.clone()
+            }
+            None => client.scopes.join(" "),
+        };
+
+        let claims = ClientClaims::new(&client.client_id, scope.clone(), CLIENT_TOKEN_LIFETIME);
+        let access_token = jwt_issuer
+            .sign(&claims)
+            .http_log_result("jwt error", StatusCode::INTERNAL_SERVER_ERROR)?;
+        metrics::TOKENS_ISSUED.with_label_values(&["client"]).inc();
+
+        info!("issued machine token for client {}", client.client_id);
+        Ok(OidcTokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: CLIENT_TOKEN_LIFETIME.whole_seconds(),
+            scope,
+            id_token: None,
+        })
+    }
+
+    /// Token introspection for services that do not verify tokens themselves.
+    /// Only confidential clients may introspect tokens.
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer, basic))]
+    pub async fn introspect(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        basic: Option<BasicAuth>,
+        request: web::Form<IntrospectionRequest>,
+    ) -> Result<Json<IntrospectionResponse>> {
+        let client =
+            authenticate_client(&mongo, basic, &request.client_id, &request.client_secret).await?;
+        if client.secret.is_none() {
+            return Err(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"));
+        }
+        trace!("introspection by client {}", client.client_id);
+
+        if let Ok((claims, user)) = jwt_issuer
+            .validate_oidc(&users, &mongo, &request.token)
+            .await
+        {
+            return Ok(Json(IntrospectionResponse {
+                active: true,
+                sub: Some(claims.sub),
+                user_id: Some(claims.user_id),
+                roles: Some(
+                    mongo
+                        .effective_roles(&user)
+                        .await
+                        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?,
+                ),
+                scope: claims.scope,
+                exp: Some(claims.exp),
+                token_type: Some("Bearer".to_string()),
+                ..Default::default()
+            }));
+        }
+
+        if let Ok(claims) = jwt_issuer.decode_client(&request.token).await {
+            if mongo.get_client(&claims.client_id).await.is_ok() {
+                return Ok(Json(IntrospectionResponse {
+                    active: true,
+                    sub: Some(claims.client_id.clone()),
+                    client_id: Some(claims.client_id),
+                    scope: Some(claims.scope),
+                    exp: Some(claims.exp),
+                    token_type: Some("Bearer".to_string()),
+                    ..Default::default()
+                }));
+            }
+        }
+
+        Ok(Json(IntrospectionResponse::default()))
+    }
+
+    #[tracing::instrument(level = "trace", skip(users, mongo, claims))]
+    pub async fn userinfo(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        claims: ReqData<UserClaims>,
+    ) -> Result<Json<UserInfoClaims>> {
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::UNAUTHORIZED)?;
+        let roles = mongo
+            .effective_roles(&user)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(Json(UserInfoClaims::new(user.into(), roles, &claims)))
+    }
+
+    #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn register_client(
+        mongo: Data<Arc<Mongo>>,
+        request: Json<RegisteringClient>,
+    ) -> Result<Json<ClientCredentials>> {
+        if request
+            .grant_types
+            .iter()
+            .any(|g| g == GRANT_CLIENT_CREDENTIALS)
+            && !request.confidential
+        {
+            return Err(error::InternalError::new(
+                "client credentials need a confidential client",
+                StatusCode::BAD_REQUEST,
+            )
+            .into());
+        }
+        let secret = if request.confidential {
+            Some(crypto::random_token())
+        } else {
+            None
+        };
+        let client = OidcClient {
+            client_id: Uuid::new_v4().to_string(),
+            name: request.0.name,
+            secret: secret.as_deref().map(crypto::hash),
+            redirect_uris: request.0.redirect_uris,
+            grant_types: request.0.grant_types,
+            scopes: request.0.scopes,
+        };
+        mongo
+            .create_client(&client)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(Json(ClientCredentials {
+            client_id: client.client_id,
+            client_secret: secret,
+        }))
+    }
+
+    #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn list_clients(mongo: Data<Arc<Mongo>>) -> Result<Json<Vec<ClientInfo>>> {
+        mongo
+            .get_all_clients()
+            .await
+            .map(|v| Json(v.into_iter().map(ClientInfo::from).collect()))
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn delete_client(
+        mongo: Data<Arc<Mongo>>,
+        req: HttpRequest,
+    ) -> Result<impl Responder> {
+        let id = req
+            .match_info()
+            .get("id")
+            .http_result(StatusCode::BAD_REQUEST)?;
+        info!("deleting client {}", id);
+        mongo
+            .delete_client(id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        Ok(HttpResponse::Ok())
+    }
+}
@@ -0,0 +1,333 @@
+use crate::api::lockout::{self, LoginKeys};
+use crate::api::{issue_tokens, require_step_up, IntoHttpError};
+use crate::config::Config;
+use crate::crypto::{self, totp, JwtIssuer};
+use crate::mongo::Mongo;
+use crate::schema::{
+    MfaClaims, MfaLoginRequest, RecoveryCodes, Role, StepUpRequest, TokenResponse, TwoFactor,
+    TwoFactorCode, TwoFactorDisableRequest, TwoFactorEnrollment, TwoFactorPolicy, UserClaims,
+    UserWithHash, MFA_LOGIN,
+};
+use crate::store::UserStore;
+use actix_web::error::Result;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Json, ReqData};
+use actix_web::{error, web, HttpRequest, HttpResponse, Responder};
+use data_encoding::BASE32_NOPAD;
+use sodiumoxide::randombytes::randombytes;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+/// Issuer shown in authenticator apps.
+const TOTP_ISSUER: &str = "message-board";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Generates recovery codes, returns the codes and their hashes.
+fn recovery_codes() -> (Vec<String>, Vec<String>) {
+    sodiumoxide::init().unwrap();
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = BASE32_NOPAD.encode(&randombytes(5)).to_lowercase();
+            format!("{}-{}", &code[..4], &code[4..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+    (codes, hashes)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    crypto::hash_token(&normalized.to_lowercase())
+}
+
+/// Checks a TOTP code, or a recovery code if the enrollment is confirmed.
+/// Both can only be used once.
+async fn check_code(
+    users: &Arc<dyn UserStore>,
+    user: &UserWithHash,
+    code: &str,
+) -> anyhow::Result<bool> {
+    let two_factor = match &user.two_factor {
+        Some(two_factor) => two_factor,
+        None => return Ok(false),
+    };
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    if let Some(step) = totp::verify(&two_factor.secret, code, now) {
+        return users.claim_totp_step(&user.id, step).await;
+    }
+
+    if two_factor.confirmed
+        && users
+            .take_recovery_code(&user.id, &hash_recovery_code(code))
+            .await?
+    {
+        info!("user {} used a recovery code", user.id);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+async fn require_code(users: &Arc<dyn UserStore>, user: &UserWithHash, code: &str) -> Result<()> {
+    if check_code(users, user, code)
+        .await
+        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        Ok(())
+    } else {
+        warn!("invalid second factor for user {}", user.id);
+        Err(error::ErrorUnauthorized("invalid code"))
+    }
+}
+
+pub struct TwoFactorApi;
+
+impl TwoFactorApi {
+    /// Second step of [`crate::api::Auth::sign_in`].
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer, config, req))]
+    pub async fn sign_in(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        config: Data<Config>,
+        request: web::Json<MfaLoginRequest>,
+        req: HttpRequest,
+    ) -> Result<Json<TokenResponse>> {
+        let claims: MfaClaims = jwt_issuer
+            .decode_claims(&request.mfa_token)
+            .http_result(StatusCode::UNAUTHORIZED)?;
+        if claims.purpose != MFA_LOGIN {
+            return Err(error::ErrorUnauthorized("invalid token"));
+        }
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::UNAUTHORIZED)?;
+        // the password changed since the first step
+        if claims.ver < user.token_version {
+            return Err(error::ErrorUnauthorized("invalid token"));
+        }
+
+        // codes are guessed against the same limits as passwords
+        let keys = LoginKeys::new(&user.email, &req);
+        lockout::check(mongo.get_ref().as_ref(), &config.lockout, &keys).await?;
+        if let Err(e) = require_code(&users, &user, &request.code).await {
+            lockout::record_failure(mongo.get_ref().as_ref(), &config.lockout, &keys).await?;
+            return Err(e);
+        }
+        lockout::record_success(mongo.get_ref().as_ref(), &keys).await?;
+        Ok(Json(issue_tokens(&mongo, &jwt_issuer, user).await?))
+    }
+
+    /// Starts an enrollment, it is only active after [`TwoFactorApi::confirm`].
+    /// Needs [`require_step_up`], the secret must not end up with whoever holds a token.
+    #[tracing::instrument(level = "trace", skip(users, mongo, config, req))]
+    pub async fn enroll(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        config: Data<Config>,
+        claims: ReqData<UserClaims>,
+        step_up: Option<web::Json<StepUpRequest>>,
+        req: HttpRequest,
+    ) -> Result<Json<TwoFactorEnrollment>> {
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        if user.has_two_factor() {
+            return Err(error::ErrorConflict(
+                "two factor authentication already enabled",
+            ));
+        }
+        let step_up = step_up.map(|s| s.0).unwrap_or_default();
+        require_step_up(
+            &mongo,
+            &config,
+            &user,
+            &claims,
+            step_up.current_password.as_deref(),
+            &req,
+        )
+        .await?;
+
+        let secret = totp::generate_secret();
+        users
+            .set_two_factor(
+                &user.id,
+                Some(&TwoFactor {
+                    secret: secret.clone(),
+                    confirmed: false,
+                    last_step: 0,
+                    recovery_codes: vec![],
+                }),
+            )
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(Json(TwoFactorEnrollment {
+            uri: totp::provisioning_uri(&secret, TOTP_ISSUER, &user.email),
+            secret,
+        }))
+    }
+
+    /// Activates the enrollment with a first code and returns the recovery codes.
+    #[tracing::instrument(level = "trace", skip(users))]
+    pub async fn confirm(
+        users: Data<Arc<dyn UserStore>>,
+        claims: ReqData<UserClaims>,
+        request: web::Json<TwoFactorCode>,
+    ) -> Result<Json<RecoveryCodes>> {
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        if !matches!(&user.two_factor, Some(two_factor) if !two_factor.confirmed) {
+            return Err(error::ErrorConflict("no pending enrollment"));
+        }
+        require_code(&users, &user, &request.code).await?;
+
+        let (codes, hashes) = recovery_codes();
+        users
+            .confirm_two_factor(&user.id, &hashes)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        info!("user {} enabled two factor authentication", user.id);
+        Ok(Json(RecoveryCodes {
+            recovery_codes: codes,
+        }))
+    }
+
+    /// Replaces all recovery codes.
+    #[tracing::instrument(level = "trace", skip(users))]
+    pub async fn regenerate_recovery_codes(
+        users: Data<Arc<dyn UserStore>>,
+        claims: ReqData<UserClaims>,
+        request: web::Json<TwoFactorCode>,
+    ) -> Result<Json<RecoveryCodes>> {
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        if !user.has_two_factor() {
+            return Err(error::ErrorConflict(
+                "two factor authentication not enabled",
+            ));
+        }
+        require_code(&users, &user, &request.code).await?;
+
+        let (codes, hashes) = recovery_codes();
+        users
+            .set_recovery_codes(&user.id, &hashes)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(Json(RecoveryCodes {
+            recovery_codes: codes,
+        }))
+    }
+
+    /// Needs a code and [`require_step_up`].
+    #[tracing::instrument(level = "trace", skip(users, mongo, config, req))]
+    pub async fn disable(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        config: Data<Config>,
+        claims: ReqData<UserClaims>,
+        request: web::Json<TwoFactorDisableRequest>,
+        req: HttpRequest,
+    ) -> Result<impl Responder> {
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        if !user.has_two_factor() {
+            return Err(error::ErrorConflict(
+                "two factor authentication not enabled",
+            ));
+        }
+        require_step_up(
+            &mongo,
+            &config,
+            &user,
+            &claims,
+            request.current_password.as_deref(),
+            &req,
+        )
+        .await?;
+        require_code(&users, &user, &request.code).await?;
+        users
+            .set_two_factor(&user.id, None)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        info!("user {} disabled two factor authentication", user.id);
+        Ok(HttpResponse::Ok())
+    }
+
+    #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn get_policy(mongo: Data<Arc<Mongo>>) -> Result<Json<TwoFactorPolicy>> {
+        Ok(Json(
+            mongo
+                .get_two_factor_policy()
+                .await
+                .http_result(StatusCode::INTERNAL_SERVER_ERROR)?,
+        ))
+    }
+
+    /// Sets the roles that require two factor authentication.
+    /// Users without it keep their account but lose these roles until they enroll.
+    #[tracing::instrument(level = "trace", skip(users, mongo))]
+    pub async fn set_policy(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        claims: ReqData<UserClaims>,
+        policy: web::Json<TwoFactorPolicy>,
+    ) -> Result<Json<TwoFactorPolicy>> {
+        // users need their user role to enroll
+        if policy.required_for.contains(&Role::User) {
+            return Err(error::ErrorBadRequest(
+                "can not require two factor authentication for users",
+            ));
+        }
+        let admin = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        if policy.required_for.contains(&Role::Admin) && !admin.has_two_factor() {
+            return Err(error::ErrorConflict(
+                "enable two factor authentication before requiring it for admins",
+            ));
+        }
+
+        mongo
+            .set_two_factor_policy(&policy)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        info!(
+            "two factor authentication required for {:?}",
+            policy.required_for
+        );
+        Ok(Json(policy.0))
+    }
+
+    /// Removes the two factor authentication of a user that lost access to it.
+    #[tracing::instrument(level = "trace", skip(users))]
+    pub async fn reset(
+        users: Data<Arc<dyn UserStore>>,
+        req: HttpRequest,
+    ) -> Result<impl Responder> {
+        let id = req
+            .match_info()
+            .get("id")
+            .http_result(StatusCode::BAD_REQUEST)?;
+        users
+            .require_user(id)
+            .await
+            .http_result(StatusCode::NOT_FOUND)?;
+        users
+            .set_two_factor(id, None)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        warn!("two factor authentication of user {} reset by admin", id);
+        Ok(HttpResponse::Ok())
+    }
+}
@@ -0,0 +1,424 @@
+use crate::api::{issue_tokens, require_step_up, IntoHttpError};
+use crate::config::Config;
+use crate::crypto::JwtIssuer;
+use crate::crypto::{self, webauthn};
+use crate::mongo::Mongo;
+use crate::schema::{
+    AccountStatus, AuthenticatorSelection, CredentialCreationOptions, CredentialDescriptor,
+    CredentialParameters, CredentialRequestOptions, LoginCredentialRequest,
+    RegisterCredentialRequest, RelyingPartyEntity, StepUpRequest, TokenResponse, UserClaims,
+    WebauthnChallenge, WebauthnCredential, WebauthnCredentialInfo, WebauthnLoginStart,
+    WebauthnUserEntity,
+};
+use crate::store::UserStore;
+use actix_web::error::Result;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Json, ReqData};
+use actix_web::{error, web, HttpRequest, HttpResponse, Responder};
+use mongodb::bson::DateTime;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+
+const CEREMONY_LIFETIME: Duration = Duration::minutes(5);
+
+/// Creates and stores a challenge for a ceremony.
+async fn new_challenge(mongo: &Mongo, ceremony: &str, user_id: Option<String>) -> Result<String> {
+    let challenge = crypto::random_token();
+    mongo
+        .insert_webauthn_challenge(&WebauthnChallenge {
+            hash: crypto::hash_token(&challenge),
+            ceremony: ceremony.to_string(),
+            user_id,
+            expires_at: DateTime::from_millis(
+                (OffsetDateTime::now_utc() + CEREMONY_LIFETIME).unix_timestamp() * 1000,
+            ),
+        })
+        .await
+        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(challenge)
+}
+
+/// Checks the client data and consumes the challenge it answers.
+async fn take_challenge(
+    mongo: &Mongo,
+    config: &Config,
+    client_data_json: &[u8],
+    ceremony: &str,
+) -> Result<WebauthnChallenge> {
+    let challenge = webauthn::check_client_data(&config.webauthn, client_data_json, ceremony)
+        .http_log_result("invalid client data", StatusCode::BAD_REQUEST)?;
+    mongo
+        .take_webauthn_challenge(&crypto::hash_token(&challenge), ceremony)
+        .await
+        .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+        // expired challenges may not have been removed by mongo yet
+        .filter(|c| c.expires_at > DateTime::now())
+        .ok_or_else(|| error::ErrorBadRequest("unknown challenge"))
+}
+
+pub struct WebauthnApi;
+
+impl WebauthnApi {
+    /// Starts the registration of a new credential for the signed in user.
+    /// Both steps of the registration need [`require_step_up`].
+    #[tracing::instrument(level = "trace", skip(users, mongo, config, req))]
+    pub async fn register_start(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        config: Data<Config>,
+        claims: ReqData<UserClaims>,
+        step_up: Option<web::Json<StepUpRequest>>,
+        req: HttpRequest,
+    ) -> Result<Json<CredentialCreationOptions>> {
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        let step_up = step_up.map(|s| s.0).unwrap_or_default();
+        require_step_up(
+            &mongo,
+            &config,
+            &user,
+            &claims,
+            step_up.current_password.as_deref(),
+            &req,
+        )
+        .await?;
+        let existing = mongo
+            .get_webauthn_credentials(&user.id)
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let challenge = new_challenge(&mongo, webauthn::CREATE, Some(user.id.clone())).await?;
+
+        Ok(Json(CredentialCreationOptions {
+            rp: RelyingPartyEntity {
+                id: config.webauthn.rp_id.clone(),
+                name: config.webauthn.rp_name.clone(),
+            },
+            user: WebauthnUserEntity {
+                id: webauthn::encode_b64(user.id.as_bytes()),
+                name: user.email,
+                display_name: user.name,
+            },
+            challenge,
+            pub_key_cred_params: vec![CredentialParameters {
+                kind: "public-key".into(),
+                alg: webauthn::ES256,
+            }],
+            timeout: CEREMONY_LIFETIME.whole_milliseconds() as u32,
+            attestation: "none".into(),
+            exclude_credentials: existing
+                .into_iter()
+                .map(|c| CredentialDescriptor::public_key(c.credential_id))
+                .collect(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "preferred".into(),
+                user_verification: "preferred".into(),
+            },
+        }))
+    }
+
+    #[tracing::instrument(level = "trace", skip(users, mongo, config, req))]
+    pub async fn register_finish(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        config: Data<Config>,
+        claims: ReqData<UserClaims>,
+        request: web::Json<RegisterCredentialRequest>,
+        req: HttpRequest,
+    ) -> Result<Json<WebauthnCredentialInfo>> {
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .http_result(StatusCode::BAD_REQUEST)?;
+        require_step_up(
+            &mongo,
+            &config,
+            &user,
+            &claims,
+            request.current_password.as_deref(),
+            &req,
+        )
+        .await?;
+
+        let client_data_json = webauthn::decode_b64(&request.response.client_data_json)
+            .http_result(StatusCode::BAD_REQUEST)?;
+        let challenge =
+            take_challenge(&mongo, &config, &client_data_json, webauthn::CREATE).await?;
+        if challenge.user_id.as_deref() != Some(claims.user_id.as_str()) {
+            return Err(error::ErrorBadRequest("unknown challenge"));
+        }
+
+        let attestation_object = webauthn::decode_b64(&request.response.attestation_object)
+            .http_result(StatusCode::BAD_REQUEST)?;
+        let new_credential = webauthn::verify_registration(&config.webauthn, &attestation_object)
+            .http_log_result("invalid attestation", StatusCode::BAD_REQUEST)?;
+
+        let credential = WebauthnCredential {
+            credential_id: webauthn::encode_b64(&new_credential.id),
+            user_id: claims.user_id.clone(),
+            public_key: webauthn::encode_b64(&new_credential.public_key),
+            sign_count: new_credential.sign_count,
+            name: request.name.clone().unwrap_or_else(|| "passkey".into()),
+            created_at: DateTime::now(),
+        };
+        mongo
+            .insert_webauthn_credential(&credential)
+            .await
+            .http_log_result("credential already registered", StatusCode::CONFLICT)?;
+        Ok(Json(credential.into()))
+    }
+
+    /// Starts a login. Without an email any discoverable credential is accepted.
+    #[tracing::instrument(level = "trace", skip(users, mongo, config))]
+    pub async fn login_start(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        config: Data<Config>,
+        request: web::Json<WebauthnLoginStart>,
+    ) -> Result<Json<CredentialRequestOptions>> {
+        // unknown addresses get an empty list, same as a login without email
+        let user = match &request.email {
+            Some(email) => users.require_user_by_email(email).await.ok(),
+            None => None,
+        };
+        let allow_credentials = match &user {
+            Some(user) => mongo
+                .get_webauthn_credentials(&user.id)
+                .await
+                .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+                .into_iter()
+                .map(|c| CredentialDescriptor::public_key(c.credential_id))
+                .collect(),
+            None => vec![],
+        };
+        let challenge = new_challenge(&mongo, webauthn::GET, user.map(|u| u.id)).await?;
+
+        Ok(Json(CredentialRequestOptions {
+            challenge,
+            rp_id: config.webauthn.rp_id.clone(),
+            timeout: CEREMONY_LIFETIME.whole_milliseconds() as u32,
+            user_verification: "preferred".into(),
+            allow_credentials,
+        }))
+    }
+
+    /// Checks an assertion and signs the user in like [`crate::api::Auth::sign_in`].
+    #[tracing::instrument(level = "trace", skip(users, mongo, jwt_issuer, config))]
+    pub async fn login_finish(
+        users: Data<Arc<dyn UserStore>>,
+        mongo: Data<Arc<Mongo>>,
+        jwt_issuer: Data<Arc<JwtIssuer>>,
+        config: Data<Config>,
+        request: web::Json<LoginCredentialRequest>,
+    ) -> Result<Json<TokenResponse>> {
+        let client_data_json = webauthn::decode_b64(&request.response.client_data_json)
+            .http_result(StatusCode::BAD_REQUEST)?;
+        let challenge = take_challenge(&mongo, &config, &client_data_json, webauthn::GET).await?;
+
+        let credential = mongo
+            .get_webauthn_credential(&request.id)
+            .await
+            .http_result(StatusCode::UNAUTHORIZED)?;
+        if challenge
+            .user_id
+            .is_some_and(|user_id| user_id != credential.user_id)
+        {
+            return Err(error::ErrorUnauthorized(""));
+        }
+
+        let authenticator_data = webauthn::decode_b64(&request.response.authenticator_data)
+            .http_result(StatusCode::BAD_REQUEST)?;
+        let signature = webauthn::decode_b64(&request.response.signature)
+            .http_result(StatusCode::BAD_REQUEST)?;
+        let public_key = webauthn::decode_b64(&credential.public_key)
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sign_count = webauthn::verify_login(
+            &config.webauthn,
+            &public_key,
+            credential.sign_count,
+            &authenticator_data,
+            &client_data_json,
+            &signature,
+        )
+        .http_log_result("invalid assertion", StatusCode::UNAUTHORIZED)?;
+        if !mongo
+            .update_webauthn_sign_count(
+                &credential.credential_id,
+                credential.sign_count,
+                sign_count,
+            )
+            .await
+            .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            return Err(error::ErrorUnauthorized(""));
+        }
+
+        let user = users
+            .require_user(&credential.user_id)
+            .await
+            .http_result(StatusCode::UNAUTHORIZED)?;
+        if user.status == AccountStatus::Pending {
+            return Err(error::ErrorForbidden("email not verified"));
+        }
+        info!("user {} signed in with a passkey", user.id);
+        Ok(Json(issue_tokens(&mongo, &jwt_issuer, user).await?))
+    }
+
+    #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn list_credentials(
+        mongo: Data<Arc<Mongo>>,
+        claims: ReqData<UserClaims>,
+    ) -> Result<Json<Vec<WebauthnCredentialInfo>>> {
+        Ok(Json(
+            mongo
+                .get_webauthn_credentials(&claims.user_id)
+                .await
+                .http_result(StatusCode::INTERNAL_SERVER_ERROR)?
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        ))
+    }
+
+    #[tracing::instrument(level = "trace", skip(mongo))]
+    pub async fn delete_credential(
+        mongo: Data<Arc<Mongo>>,
+        claims: ReqData<UserClaims>,
+        req: HttpRequest,
+    ) -> Result<impl Responder> {
+        let id = req
+            .match_info()
+            .get("id")
+            .http_result(StatusCode::BAD_REQUEST)?;
+        mongo
+            .delete_webauthn_credential(&claims.user_id, id)
+            .await
+            .http_result(StatusCode::NOT_FOUND)?;
+        Ok(HttpResponse::Ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::{test, App, HttpMessage};
+
+    #[actix_web::test]
+    async fn registration_needs_a_recent_sign_in() {
+        let config = testing::config();
+        let users = testing::users();
+        let user = testing::user("user@board.test", "password");
+        users.create_user(&user).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(users))
+                .app_data(Data::new(testing::mongo(&config).await))
+                .app_data(Data::new(config))
+                .route("/start", web::post().to(WebauthnApi::register_start)),
+        )
+        .await;
+
+        let mut claims = UserClaims::from(user);
+        claims.auth_time -= 3600;
+        let request = test::TestRequest::post().uri("/start").to_request();
+        request.extensions_mut().insert(claims);
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a mongo server, see testing::live_mongo"]
+    async fn registers_and_signs_in_with_a_passkey() {
+        let config = testing::config();
+        let users = testing::users();
+        let user = testing::user("passkey@board.test", "password");
+        users.create_user(&user).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(users))
+                .app_data(Data::new(testing::live_mongo(&config).await))
+                .app_data(Data::new(testing::jwt_issuer(&config).await))
+                .app_data(Data::new(config.clone()))
+                .route(
+                    "/register/start",
+                    web::post().to(WebauthnApi::register_start),
+                )
+                .route(
+                    "/register/finish",
+                    web::post().to(WebauthnApi::register_finish),
+                )
+                .route("/login/start", web::post().to(WebauthnApi::login_start))
+                .route("/login/finish", web::post().to(WebauthnApi::login_finish)),
+        )
+        .await;
+        let authenticator = testing::SoftAuthenticator::new(&config.webauthn.rp_id);
+        let credential_id = webauthn::encode_b64(&authenticator.credential_id);
+        let signed_in = |request: test::TestRequest| {
+            let request = request.to_request();
+            request
+                .extensions_mut()
+                .insert(UserClaims::from(user.clone()));
+            request
+        };
+
+        let options: CredentialCreationOptions = test::call_and_read_body_json(
+            &app,
+            signed_in(test::TestRequest::post().uri("/register/start")),
+        )
+        .await;
+        let client_data = testing::SoftAuthenticator::client_data(
+            webauthn::CREATE,
+            &options.challenge,
+            &config.webauthn.origin,
+        );
+        let request = test::TestRequest::post().uri("/register/finish").set_json(
+            serde_json::json!({
+                "id": credential_id,
+                "response": {
+                    "clientDataJSON": webauthn::encode_b64(&client_data),
+                    "attestationObject": webauthn::encode_b64(&authenticator.attestation_object(0)),
+                },
+            }),
+        );
+        let response = test::call_service(&app, signed_in(request)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let options: CredentialRequestOptions = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::post()
+                .uri("/login/start")
+                .set_json(serde_json::json!({"email": "Passkey@board.test"}))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(options.allow_credentials.len(), 1);
+        assert_eq!(options.allow_credentials[0].id, credential_id);
+        let client_data = testing::SoftAuthenticator::client_data(
+            webauthn::GET,
+            &options.challenge,
+            &config.webauthn.origin,
+        );
+        let (authenticator_data, signature) = authenticator.assert(&client_data, 1);
+        let tokens: TokenResponse = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::post()
+                .uri("/login/finish")
+                .set_json(serde_json::json!({
+                    "id": credential_id,
+                    "response": {
+                        "clientDataJSON": webauthn::encode_b64(&client_data),
+                        "authenticatorData": webauthn::encode_b64(&authenticator_data),
+                        "signature": webauthn::encode_b64(&signature),
+                        "userHandle": null,
+                    },
+                }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(tokens.user.id, user.id);
+        assert!(tokens.refresh_token.is_some());
+    }
+}
@@ -1,72 +1,719 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use derivative::Derivative;
-use serde::Deserialize;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
 use std::env;
+use std::fmt::{self, Display};
 use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
 
-#[derive(Deserialize, Clone, Derivative)]
+#[derive(Deserialize, Serialize, Clone, Derivative)]
 #[derivative(Debug)]
 pub struct Config {
+    pub server: ServerConfig,
+    pub tracing: TracingConfig,
     pub image_service: ImageServiceConfig,
     pub db: DB,
     pub default_user: DefaultUser,
     #[derivative(Debug = "ignore")]
+    #[serde(rename = "jwt")]
     pub jwt_config: JwtSecret,
+    pub oidc: OidcConfig,
+    pub smtp: SmtpConfig,
+    pub webauthn: WebauthnConfig,
+    pub lockout: LockoutConfig,
+    pub rate_limit: RateLimitConfig,
+    pub password_policy: PasswordPolicyConfig,
+    /// Base url used in links sent by mail.
+    pub public_url: String,
+    pub user_store: UserStoreBackend,
+    /// Apply pending migrations at startup, unset with `skip_migrations`.
+    pub migrate_on_startup: bool,
 }
 
+/// Default location of the config file, relative to the working directory.
+const CONFIG_PATH: &str = "auth_config.toml";
+const REDACTED: &str = "<redacted>";
+
 impl Config {
+    /// Reads the config file (`AUTH_CONFIG`, default `auth_config.toml`), overridden by environment variables.
+    /// Secrets can also be read from the file named by `<NAME>_FILE`, which takes precedence.
     #[tracing::instrument(level = "trace")]
-    pub fn from_env() -> Result<Self> {
+    pub fn load() -> Result<Self> {
+        Self::from_source(Source::load()?)
+    }
+
+    /// Reads the config from `content` instead of the config file and ignores the environment, for tests.
+    #[cfg(test)]
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Self::from_source(Source {
+            path: "test config".to_string(),
+            file: toml::from_str(content)?,
+            vars: HashMap::new(),
+        })
+    }
+
+    fn from_source(source: Source) -> Result<Self> {
         let config = Config {
+            server: ServerConfig {
+                address: source.string("SERVER_ADDRESS", "server.address", "0.0.0.0")?,
+                port: source.parse("SERVER_PORT", "server.port", 8080)?,
+                workers: source.parse_option("SERVER_WORKERS", "server.workers")?,
+                keep_alive: source.parse("SERVER_KEEP_ALIVE", "server.keep_alive", 5)?,
+                body_limit: source.parse("SERVER_BODY_LIMIT", "server.body_limit", 262_144)?,
+                trusted_proxies: source
+                    .string("SERVER_TRUSTED_PROXIES", "server.trusted_proxies", "")?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| {
+                        s.parse().with_context(|| {
+                            format!(
+                                "server.trusted_proxies (SERVER_TRUSTED_PROXIES): invalid entry {}",
+                                s
+                            )
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                tls: match (
+                    source.get("TLS_CERT_PATH", "server.tls.cert_path")?,
+                    source.get("TLS_KEY_PATH", "server.tls.key_path")?,
+                ) {
+                    (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                        cert_path,
+                        key_path,
+                    }),
+                    (None, None) => None,
+                    _ => bail!(
+                        "server.tls.cert_path (TLS_CERT_PATH) and server.tls.key_path (TLS_KEY_PATH) \
+                         have to be set together"
+                    ),
+                },
+            },
+            tracing: TracingConfig {
+                exporter: match source.string("TRACING_EXPORTER", "tracing.exporter", "none")?.as_str() {
+                    "none" => TraceExporter::None,
+                    "jaeger" => TraceExporter::Jaeger,
+                    "otlp-grpc" => TraceExporter::OtlpGrpc,
+                    "otlp-http" => TraceExporter::OtlpHttp,
+                    other => bail!(
+                        "tracing.exporter (TRACING_EXPORTER): unknown exporter {}, \
+                         expected none, jaeger, otlp-grpc or otlp-http",
+                        other
+                    ),
+                },
+                endpoint: source.get("TRACING_ENDPOINT", "tracing.endpoint")?,
+                service_name: source.string(
+                    "TRACING_SERVICE_NAME",
+                    "tracing.service_name",
+                    "auth-service",
+                )?,
+                sample_ratio: source.parse("TRACING_SAMPLE_RATIO", "tracing.sample_ratio", 1.0)?,
+                log_format: match source.string("LOG_FORMAT", "tracing.log_format", "human")?.as_str() {
+                    "human" => LogFormat::Human,
+                    "json" => LogFormat::Json,
+                    other => bail!(
+                        "tracing.log_format (LOG_FORMAT): unknown format {}, expected human or json",
+                        other
+                    ),
+                },
+                log_filter: source.string("RUST_LOG", "tracing.log_filter", "info")?,
+            },
             image_service: ImageServiceConfig {
-                url: env::var("IMAGE_SERVICE_URL")?,
+                url: source.required("IMAGE_SERVICE_URL", "image_service.url")?,
             },
             db: DB {
-                address: std::env::var("DB_ADDRESS")?,
-                port: std::env::var("DB_PORT")?,
-                user: std::env::var("DB_USER")?,
-                password: std::env::var("DB_PASSWORD")?,
+                address: source.string("DB_ADDRESS", "db.address", "localhost")?,
+                port: source.string("DB_PORT", "db.port", "27017")?,
+                user: source.required("DB_USER", "db.user")?,
+                password: source
+                    .secret("DB_PASSWORD", "db.password")?
+                    .ok_or_else(|| source.missing("DB_PASSWORD", "db.password"))?,
             },
             default_user: DefaultUser {
-                name: std::env::var("DEFAULT_USER")?,
-                pass: std::env::var("DEFAULT_PASSWORD")?,
-                create: (std::env::var("CREATE_DEFAULT_USER").is_ok()),
+                name: source.string("DEFAULT_USER", "default_user.name", "admin")?,
+                pass: source
+                    .secret("DEFAULT_PASSWORD", "default_user.pass")?
+                    .ok_or_else(|| source.missing("DEFAULT_PASSWORD", "default_user.pass"))?,
+                create: source.flag("CREATE_DEFAULT_USER", "default_user.create", false)?,
             },
-            jwt_config: if let Ok(secret) = std::env::var("JWT_SECRET") {
+            jwt_config: if let Some(dir) = source.get("JWT_KEY_DIR", "jwt.key_dir")? {
+                JwtSecret::KeyRing { dir }
+            } else if let Some(secret) = source.secret("JWT_SECRET", "jwt.secret")? {
                 JwtSecret::Pass(secret)
             } else {
-                JwtSecret::KeyPair {
-                    private: fs::read(env::var("JWT_PRIVATE_PATH")?)?,
-                    public: fs::read(env::var("JWT_PUBLIC_PATH")?)?,
+                match (
+                    source.get("JWT_PRIVATE_PATH", "jwt.private_path")?,
+                    source.get("JWT_PUBLIC_PATH", "jwt.public_path")?,
+                ) {
+                    (Some(private), Some(public)) => JwtSecret::KeyPair {
+                        private: fs::read(&private)
+                            .with_context(|| format!("can not read jwt key {}", private))?,
+                        public: fs::read(&public)
+                            .with_context(|| format!("can not read jwt key {}", public))?,
+                    },
+                    _ => bail!(
+                        "no jwt key configured, set jwt.key_dir (JWT_KEY_DIR), jwt.secret (JWT_SECRET) \
+                         or jwt.private_path and jwt.public_path (JWT_PRIVATE_PATH, JWT_PUBLIC_PATH)"
+                    ),
                 }
             },
+            oidc: OidcConfig {
+                issuer: source.string("OIDC_ISSUER", "oidc.issuer", "https://localhost")?,
+                login_url: source.string(
+                    "OIDC_LOGIN_URL",
+                    "oidc.login_url",
+                    "https://localhost/authorize",
+                )?,
+            },
+            smtp: SmtpConfig {
+                host: source.string("SMTP_HOST", "smtp.host", "localhost")?,
+                port: source.parse("SMTP_PORT", "smtp.port", 25)?,
+                user: source.get("SMTP_USER", "smtp.user")?,
+                password: source.secret("SMTP_PASSWORD", "smtp.password")?,
+                from: source.string("SMTP_FROM", "smtp.from", "noreply@localhost")?,
+                starttls: source.flag("SMTP_STARTTLS", "smtp.starttls", false)?,
+            },
+            webauthn: WebauthnConfig {
+                rp_id: source.string("WEBAUTHN_RP_ID", "webauthn.rp_id", "localhost")?,
+                rp_name: source.string("WEBAUTHN_RP_NAME", "webauthn.rp_name", "message-board")?,
+                origin: source.string("WEBAUTHN_ORIGIN", "webauthn.origin", "https://localhost")?,
+            },
+            lockout: LockoutConfig {
+                threshold: source.parse("LOCKOUT_THRESHOLD", "lockout.threshold", 5)?,
+                ip_threshold: source.parse("LOCKOUT_IP_THRESHOLD", "lockout.ip_threshold", 50)?,
+                backoff_seconds: source.parse(
+                    "LOCKOUT_BACKOFF_SECONDS",
+                    "lockout.backoff_seconds",
+                    1,
+                )?,
+                lockout_seconds: source.parse("LOCKOUT_SECONDS", "lockout.lockout_seconds", 900)?,
+            },
+            rate_limit: RateLimitConfig {
+                backend: match source
+                    .string("RATE_LIMIT_BACKEND", "rate_limit.backend", "memory")?
+                    .as_str()
+                {
+                    "memory" => RateLimitBackend::Memory,
+                    "mongo" => RateLimitBackend::Mongo,
+                    other => bail!(
+                        "rate_limit.backend (RATE_LIMIT_BACKEND): unknown backend {}, expected memory or mongo",
+                        other
+                    ),
+                },
+                signin: source.parse("RATE_LIMIT_SIGNIN", "rate_limit.signin", "10/60".parse()?)?,
+                signup: source.parse("RATE_LIMIT_SIGNUP", "rate_limit.signup", "5/3600".parse()?)?,
+                reissue: source.parse("RATE_LIMIT_REISSUE", "rate_limit.reissue", "30/60".parse()?)?,
+                user_email: source.parse(
+                    "RATE_LIMIT_USER_EMAIL",
+                    "rate_limit.user_email",
+                    "60/60".parse()?,
+                )?,
+                get_batch: source.parse(
+                    "RATE_LIMIT_GET_BATCH",
+                    "rate_limit.get_batch",
+                    "120/60".parse()?,
+                )?,
+                mail: source.parse("RATE_LIMIT_MAIL", "rate_limit.mail", "5/3600".parse()?)?,
+            },
+            password_policy: PasswordPolicyConfig {
+                min_length: source.parse("PASSWORD_MIN_LENGTH", "password_policy.min_length", 10)?,
+                min_score: source.parse("PASSWORD_MIN_SCORE", "password_policy.min_score", 3)?,
+                breached_list: source
+                    .get("PASSWORD_BREACHED_LIST", "password_policy.breached_list")?,
+            },
+            public_url: source.string("PUBLIC_URL", "public_url", "https://localhost")?,
+            user_store: match source.string("USER_STORE", "user_store", "mongo")?.as_str() {
+                "mongo" => UserStoreBackend::Mongo,
+                "memory" => UserStoreBackend::Memory,
+                "sqlite" => UserStoreBackend::Sqlite {
+                    path: source.string("USER_STORE_PATH", "user_store_path", "users.db")?,
+                },
+                other => bail!(
+                    "user_store (USER_STORE): unknown store {}, expected mongo, memory or sqlite",
+                    other
+                ),
+            },
+            migrate_on_startup: !source.flag("SKIP_MIGRATIONS", "skip_migrations", false)?,
         };
 
+        config.validate()?;
         tracing::debug!("{:?}", config);
         Ok(config)
     }
+
+    /// Checks values that parse but can not work, all problems are reported at once.
+    fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.server.workers == Some(0) {
+            problems.push("server.workers has to be positive".to_string());
+        }
+        if self.server.body_limit == 0 {
+            problems.push("server.body_limit has to be positive".to_string());
+        }
+        for (key, url) in [
+            ("public_url", &self.public_url),
+            ("oidc.issuer", &self.oidc.issuer),
+            ("oidc.login_url", &self.oidc.login_url),
+            ("webauthn.origin", &self.webauthn.origin),
+        ] {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                problems.push(format!("{} has to be an http(s) url, got {}", key, url));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.tracing.sample_ratio) {
+            problems.push(format!(
+                "tracing.sample_ratio has to be between 0 and 1, got {}",
+                self.tracing.sample_ratio
+            ));
+        }
+        if let Err(e) = EnvFilter::try_new(&self.tracing.log_filter) {
+            problems.push(format!("tracing.log_filter is invalid: {}", e));
+        }
+        if self.db.port.parse::<u16>().is_err() {
+            problems.push(format!(
+                "db.port has to be a port number, got {}",
+                self.db.port
+            ));
+        }
+        if self.default_user.name.is_empty() {
+            problems.push("default_user.name can not be empty".to_string());
+        }
+        if self.password_policy.min_score > 4 {
+            problems.push(format!(
+                "password_policy.min_score has to be between 0 and 4, got {}",
+                self.password_policy.min_score
+            ));
+        }
+        if self.lockout.threshold == 0 || self.lockout.ip_threshold == 0 {
+            problems.push("lockout thresholds have to be positive".to_string());
+        }
+        if self.lockout.backoff_seconds < 0 || self.lockout.lockout_seconds < 0 {
+            problems.push("lockout durations can not be negative".to_string());
+        }
+        if self.smtp.user.is_some() != self.smtp.password.is_some() {
+            problems.push("smtp.user and smtp.password have to be set together".to_string());
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "invalid configuration:\n  {}",
+            problems.join("\n  ")
+        ))
+    }
+
+    /// Whether the users or the rate limits are kept in mongo, startup then waits for it.
+    /// The other collections are only used by the routes that need them.
+    pub fn stores_in_mongo(&self) -> bool {
+        self.user_store.is_mongo() || matches!(self.rate_limit.backend, RateLimitBackend::Mongo)
+    }
+
+    /// The effective configuration as TOML, for `--print-config`. Secrets are replaced by `<redacted>`.
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        // going through a value orders plain values before tables as toml requires
+        Ok(toml::to_string_pretty(&toml::Value::try_from(self)?)?)
+    }
+}
+
+/// The config file and the environment, see [`Config::load`].
+/// Keys name the value in the file, e.g. `db.port`, next to the variable overriding it.
+struct Source {
+    path: String,
+    file: toml::value::Table,
+    /// The environment when the config was loaded.
+    vars: HashMap<String, String>,
 }
 
-#[derive(Deserialize, Clone, Derivative)]
+impl Source {
+    fn load() -> Result<Self> {
+        // the default file is optional, one named explicitly has to exist
+        let (path, required) = match env::var("AUTH_CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => (CONFIG_PATH.to_string(), false),
+        };
+        let file = match fs::read_to_string(&path) {
+            Ok(content) => {
+                toml::from_str(&content).with_context(|| format!("can not parse {}", path))?
+            }
+            Err(e) if !required && e.kind() == io::ErrorKind::NotFound => toml::value::Table::new(),
+            Err(e) => bail!("can not read {}: {}", path, e),
+        };
+        // variables that are not unicode are ignored like env::var does
+        let vars = env::vars_os()
+            .filter_map(|(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Ok(Self { path, file, vars })
+    }
+
+    fn get(&self, var: &str, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.vars.get(var) {
+            return Ok(Some(value.clone()));
+        }
+        let mut value = None;
+        let mut table = Some(&self.file);
+        for part in key.split('.') {
+            value = table.and_then(|t| t.get(part));
+            table = value.and_then(toml::Value::as_table);
+        }
+        match value {
+            None => Ok(None),
+            Some(toml::Value::String(s)) => Ok(Some(s.clone())),
+            Some(
+                v @ (toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_)),
+            ) => Ok(Some(v.to_string())),
+            Some(_) => bail!("{} in {} has to be a single value", key, self.path),
+        }
+    }
+
+    /// Like [`Source::get`], but `<var>_FILE` can name a file holding the value.
+    fn secret(&self, var: &str, key: &str) -> Result<Option<String>> {
+        let file_var = format!("{}_FILE", var);
+        match self.vars.get(&file_var) {
+            Some(path) => fs::read_to_string(path)
+                .map(|s| Some(s.trim_end_matches(&['\r', '\n'][..]).to_string()))
+                .map_err(|e| anyhow!("{}: can not read {}: {}", file_var, path, e)),
+            None => self.get(var, key),
+        }
+    }
+
+    fn missing(&self, var: &str, key: &str) -> anyhow::Error {
+        anyhow!("missing {}, set it in {} or with {}", key, self.path, var)
+    }
+
+    fn required(&self, var: &str, key: &str) -> Result<String> {
+        self.get(var, key)?.ok_or_else(|| self.missing(var, key))
+    }
+
+    fn string(&self, var: &str, key: &str, default: &str) -> Result<String> {
+        Ok(self.get(var, key)?.unwrap_or_else(|| default.to_string()))
+    }
+
+    fn parse<T>(&self, var: &str, key: &str, default: T) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        Ok(self.parse_option(var, key)?.unwrap_or(default))
+    }
+
+    fn parse_option<T>(&self, var: &str, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.get(var, key)?
+            .map(|value| {
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|e| anyhow!("{} ({}): invalid value {:?}: {}", key, var, value, e))
+            })
+            .transpose()
+    }
+
+    /// Set but empty variables count as true, like the presence flags used before.
+    fn flag(&self, var: &str, key: &str, default: bool) -> Result<bool> {
+        match self.get(var, key)?.as_deref().map(str::trim) {
+            None => Ok(default),
+            Some("" | "true" | "1" | "yes") => Ok(true),
+            Some("false" | "0" | "no") => Ok(false),
+            Some(other) => bail!("{} ({}): expected true or false, got {:?}", key, var, other),
+        }
+    }
+}
+
+fn redact<T, S: Serializer>(_: &T, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+fn redact_option<T, S: Serializer>(
+    value: &Option<T>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    /// Worker threads, one per physical core if unset.
+    pub workers: Option<usize>,
+    /// Seconds an idle connection is kept open.
+    pub keep_alive: u64,
+    /// Largest accepted request body in bytes.
+    pub body_limit: usize,
+    /// Peers whose `X-Forwarded-For` is believed, see [`crate::api::middleware::client_ip`].
+    pub trusted_proxies: Vec<IpRange>,
+    /// Serve https directly instead of behind a proxy terminating TLS.
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TracingConfig {
+    pub exporter: TraceExporter,
+    /// Collector address, the default of the exporter if unset.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+    /// Share of new traces that are recorded, traces started by a caller follow its decision.
+    pub sample_ratio: f64,
+    pub log_format: LogFormat,
+    /// `EnvFilter` directives for the log written to stdout, e.g. `info,auth_service=debug`.
+    pub log_filter: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceExporter {
+    None,
+    Jaeger,
+    OtlpGrpc,
+    OtlpHttp,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+/// PEM files of the certificate chain and its private key, reloaded while running.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Derivative)]
 #[derivative(Debug)]
 pub struct DB {
     pub address: String,
     pub port: String,
     pub user: String,
     #[derivative(Debug = "ignore")]
+    #[serde(serialize_with = "redact")]
     pub password: String,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ImageServiceConfig {
     pub url: String,
 }
 
-#[derive(Deserialize, Clone, Derivative)]
+/// `issuer` is the public base url of the service,
+/// `login_url` the page of the frontend that signs the user in and confirms an authorization request.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub login_url: String,
+}
+
+/// Relying party of passkey logins, `rp_id` is the domain credentials are bound to
+/// and `origin` the page running the ceremonies.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WebauthnConfig {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub origin: String,
+}
+
+/// Failed sign ins per account and per client ip.
+/// Accounts wait `backoff_seconds` doubling with every failure, both are locked for
+/// `lockout_seconds` once their threshold is reached.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct LockoutConfig {
+    pub threshold: u32,
+    pub ip_threshold: u32,
+    pub backoff_seconds: i64,
+    pub lockout_seconds: i64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    /// Minimum strength from 0 to 4.
+    pub min_score: u8,
+    /// File of SHA-1 hashes or directory of a k-anonymity prefix dump.
+    pub breached_list: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    /// Counters per replica.
+    Memory,
+    /// Counters shared by all replicas.
+    Mongo,
+}
+
+/// Where user records are kept, see [`crate::store`].
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStoreBackend {
+    Mongo,
+    /// Lost on restart, for tests.
+    Memory,
+    /// A local SQLite file, needs the `sqlite` feature.
+    Sqlite {
+        path: String,
+    },
+}
+
+impl UserStoreBackend {
+    /// Whether users are kept in the `users` collection, which the migrations are about.
+    pub fn is_mongo(&self) -> bool {
+        matches!(self, UserStoreBackend::Mongo)
+    }
+}
+
+/// Rate limits of the public routes.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RateLimitConfig {
+    pub backend: RateLimitBackend,
+    pub signin: Limit,
+    pub signup: Limit,
+    pub reissue: Limit,
+    pub user_email: Limit,
+    pub get_batch: Limit,
+    /// Routes that send mail.
+    pub mail: Limit,
+}
+
+/// A token bucket holding `capacity` requests that refills completely in `seconds`,
+/// written as `capacity/seconds`.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct Limit {
+    pub capacity: u32,
+    pub seconds: u32,
+}
+
+impl Limit {
+    /// Tokens added per second.
+    pub fn rate(&self) -> f64 {
+        self.capacity as f64 / self.seconds as f64
+    }
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.capacity, self.seconds)
+    }
+}
+
+impl Serialize for Limit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Limit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (capacity, seconds) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected capacity/seconds, got {}", s))?;
+        let limit = Limit {
+            capacity: capacity.trim().parse()?,
+            seconds: seconds.trim().parse()?,
+        };
+        if limit.capacity == 0 || limit.seconds == 0 {
+            return Err(anyhow!("limit {} has to be positive", s));
+        }
+        Ok(limit)
+    }
+}
+
+/// An address or a network written as `address/prefix`, e.g. `172.16.0.0/12`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct IpRange {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // dual stack sockets report ipv4 peers as mapped ipv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = (prefix as usize / 8, prefix % 8);
+    network[..bytes] == ip[..bytes]
+        && (bits == 0 || (network[bytes] ^ ip[bytes]) >> (8 - bits) == 0)
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            bail!("prefix of {} is longer than the address", s);
+        }
+        Ok(IpRange { address, prefix })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    #[derivative(Debug = "ignore")]
+    #[serde(serialize_with = "redact_option")]
+    pub password: Option<String>,
+    pub from: String,
+    pub starttls: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Derivative)]
 #[derivative(Debug)]
 pub struct DefaultUser {
     pub name: String,
     #[derivative(Debug = "ignore")]
+    #[serde(serialize_with = "redact")]
     pub pass: String,
     pub create: bool,
 }
@@ -76,4 +723,222 @@ pub struct DefaultUser {
 pub enum JwtSecret {
     Pass(String),
     KeyPair { private: Vec<u8>, public: Vec<u8> },
+    KeyRing { dir: String },
+}
+
+/// Printed like the `jwt` section of the config file, without the key material.
+impl Serialize for JwtSecret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        match self {
+            JwtSecret::Pass(_) => map.serialize_entry("secret", REDACTED)?,
+            JwtSecret::KeyPair { .. } => map.serialize_entry("private_key", REDACTED)?,
+            JwtSecret::KeyRing { dir } => map.serialize_entry("key_dir", dir)?,
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        [image_service]
+        url = "localhost:1"
+
+        [db]
+        user = "auth"
+        password = "db secret"
+
+        [default_user]
+        pass = "default secret"
+
+        [jwt]
+        secret = "jwt secret"
+    "#;
+
+    fn load(content: &str, vars: &[(&str, &str)]) -> Result<Config> {
+        Config::from_source(Source {
+            path: "test config".to_string(),
+            file: toml::from_str(content)?,
+            vars: vars
+                .iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect(),
+        })
+    }
+
+    /// A file in the temp dir that is removed again when dropped.
+    struct SecretFile(std::path::PathBuf);
+
+    impl SecretFile {
+        fn new(name: &str, content: &str) -> Self {
+            let path = env::temp_dir().join(format!("auth-config-{}-{}", std::process::id(), name));
+            fs::write(&path, content).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for SecretFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let config = load(MINIMAL, &[]).unwrap();
+        assert_eq!(config.db.user, "auth");
+        assert_eq!(config.db.address, "localhost");
+
+        let config = load(
+            MINIMAL,
+            &[
+                ("DB_USER", "other"),
+                ("DB_ADDRESS", "mongo"),
+                ("SERVER_PORT", "9000"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.db.user, "other");
+        assert_eq!(config.db.address, "mongo");
+        assert_eq!(config.server.port, 9000);
+    }
+
+    #[test]
+    fn secret_files_take_precedence() {
+        let file = SecretFile::new("db-password", "from file\n");
+        let config = load(
+            MINIMAL,
+            &[
+                ("DB_PASSWORD", "from env"),
+                ("DB_PASSWORD_FILE", file.path()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.db.password, "from file");
+
+        let config = load(MINIMAL, &[("DB_PASSWORD", "from env")]).unwrap();
+        assert_eq!(config.db.password, "from env");
+
+        let missing = format!("{}-missing", file.path());
+        let error = load(MINIMAL, &[("DB_PASSWORD_FILE", &missing)]).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("DB_PASSWORD_FILE: can not read"));
+    }
+
+    #[test]
+    fn parses_flags() {
+        for (value, expected) in [
+            ("", true),
+            ("true", true),
+            (" yes ", true),
+            ("1", true),
+            ("false", false),
+            ("no", false),
+            ("0", false),
+        ] {
+            let config = load(MINIMAL, &[("SMTP_STARTTLS", value)]).unwrap();
+            assert_eq!(config.smtp.starttls, expected, "{:?}", value);
+        }
+        let config = load(&format!("skip_migrations = true\n{}", MINIMAL), &[]).unwrap();
+        assert!(!config.migrate_on_startup);
+
+        let error = load(MINIMAL, &[("SMTP_STARTTLS", "maybe")]).unwrap_err();
+        assert!(error.to_string().contains("smtp.starttls (SMTP_STARTTLS)"));
+    }
+
+    #[test]
+    fn parses_limits() {
+        let limit: Limit = "10 / 60".parse().unwrap();
+        assert_eq!((limit.capacity, limit.seconds), (10, 60));
+        assert_eq!(limit.rate(), 10.0 / 60.0);
+        for invalid in ["10", "0/60", "10/0", "ten/60", "-1/60"] {
+            assert!(invalid.parse::<Limit>().is_err(), "{}", invalid);
+        }
+
+        let config = load(MINIMAL, &[("RATE_LIMIT_SIGNIN", "3/30")]).unwrap();
+        assert_eq!(config.rate_limit.signin.to_string(), "3/30");
+        let error = load(MINIMAL, &[("RATE_LIMIT_SIGNIN", "3")]).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("rate_limit.signin (RATE_LIMIT_SIGNIN)"));
+    }
+
+    #[test]
+    fn parses_ip_ranges() {
+        let range: IpRange = "172.16.0.0/12".parse().unwrap();
+        assert!(range.contains("172.31.255.1".parse().unwrap()));
+        assert!(!range.contains("172.32.0.1".parse().unwrap()));
+        let single: IpRange = "::1".parse().unwrap();
+        assert_eq!(single.prefix, 128);
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0/8", "localhost"] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+
+        let config = load(
+            MINIMAL,
+            &[("SERVER_TRUSTED_PROXIES", "10.0.0.0/8, 127.0.0.1")],
+        )
+        .unwrap();
+        assert_eq!(config.server.trusted_proxies.len(), 2);
+        let error = load(MINIMAL, &[("SERVER_TRUSTED_PROXIES", "10.0.0.0/8,nope")]).unwrap_err();
+        assert!(error.to_string().contains("invalid entry nope"));
+    }
+
+    #[test]
+    fn reports_all_problems_at_once() {
+        let error = load(
+            MINIMAL,
+            &[
+                ("SERVER_WORKERS", "0"),
+                ("PUBLIC_URL", "board.test"),
+                ("DB_PORT", "mongo"),
+                ("PASSWORD_MIN_SCORE", "5"),
+                ("LOCKOUT_THRESHOLD", "0"),
+                ("SMTP_USER", "mailer"),
+            ],
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.starts_with("invalid configuration:"));
+        for problem in [
+            "server.workers has to be positive",
+            "public_url has to be an http(s) url, got board.test",
+            "db.port has to be a port number, got mongo",
+            "password_policy.min_score has to be between 0 and 4, got 5",
+            "lockout thresholds have to be positive",
+            "smtp.user and smtp.password have to be set together",
+        ] {
+            assert!(error.contains(problem), "{} missing in {}", problem, error);
+        }
+    }
+
+    #[test]
+    fn printed_config_has_no_secrets() {
+        let file = SecretFile::new("smtp-password", "smtp secret");
+        let config = load(
+            MINIMAL,
+            &[("SMTP_USER", "mailer"), ("SMTP_PASSWORD_FILE", file.path())],
+        )
+        .unwrap();
+        assert_eq!(config.smtp.password.as_deref(), Some("smtp secret"));
+
+        let printed = config.to_redacted_toml().unwrap();
+        for secret in ["db secret", "default secret", "smtp secret", "jwt secret"] {
+            assert!(!printed.contains(secret), "{} printed", secret);
+        }
+        let printed: toml::Value = toml::from_str(&printed).unwrap();
+        assert_eq!(printed["db"]["password"].as_str(), Some(REDACTED));
+        assert_eq!(printed["default_user"]["pass"].as_str(), Some(REDACTED));
+        assert_eq!(printed["smtp"]["password"].as_str(), Some(REDACTED));
+        assert_eq!(printed["jwt"]["secret"].as_str(), Some(REDACTED));
+        assert_eq!(printed["db"]["user"].as_str(), Some("auth"));
+    }
 }
@@ -0,0 +1,220 @@
+use crate::config::JwtSecret;
+use crate::schema::KeyInfo;
+use anyhow::{anyhow, Context, Result};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use sodiumoxide::crypto::hash::sha256;
+use std::fs;
+use std::path::Path;
+
+/// Key id used for shared secrets, which are never published and need no thumbprint.
+const SECRET_KID: &str = "secret";
+
+/// File in a key directory that holds the name of the active key.
+const ACTIVE_FILE: &str = "active";
+
+/// A key accepted for verification.
+#[derive(Clone)]
+pub(crate) struct Key {
+    pub kid: String,
+    pub decoding_key: DecodingKey,
+    pub jwk: Option<Jwk>,
+    pub can_sign: bool,
+}
+
+impl Key {
+    fn ec(public: &[u8], can_sign: bool) -> Result<Self> {
+        let jwk = ec_public_jwk(public)?;
+        Ok(Self {
+            kid: jwk.common.key_id.clone().unwrap(),
+            decoding_key: DecodingKey::from_ec_pem(public)?,
+            jwk: Some(jwk),
+            can_sign,
+        })
+    }
+}
+
+/// The active signing key together with every key that is still accepted for verification.
+pub(crate) struct KeySet {
+    pub header: Header,
+    pub encoding_key: EncodingKey,
+    pub validation: Validation,
+    pub keys: Vec<Key>,
+}
+
+impl KeySet {
+    pub fn load(secret: &JwtSecret) -> Result<Self> {
+        match secret {
+            JwtSecret::Pass(p) => Ok(Self::from_secret(p)),
+            JwtSecret::KeyPair { private, public } => {
+                Self::ec(private, vec![Key::ec(public, true)?], 0)
+            }
+            JwtSecret::KeyRing { dir } => Self::from_key_dir(Path::new(dir)),
+        }
+    }
+
+    fn from_secret(secret: &str) -> Self {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(SECRET_KID.to_string());
+        Self {
+            header,
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            validation: Validation::default(),
+            keys: vec![Key {
+                kid: SECRET_KID.to_string(),
+                decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+                jwk: None,
+                can_sign: true,
+            }],
+        }
+    }
+
+    /// Reads a key directory containing `<name>.public.pem` files, optionally paired with
+    /// `<name>.private.pem`, and an `active` file naming the key used for signing.
+    /// All other keys stay valid for verification.
+    fn from_key_dir(dir: &Path) -> Result<Self> {
+        let active_name = fs::read_to_string(dir.join(ACTIVE_FILE))
+            .with_context(|| format!("reading active key from {:?}", dir))?
+            .trim()
+            .to_string();
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let file_name = entry?.file_name();
+            if let Some(name) = file_name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".public.pem"))
+            {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+
+        let mut keys = Vec::new();
+        let mut active = None;
+        for name in names {
+            let public = fs::read(dir.join(format!("{}.public.pem", name)))?;
+            let private = fs::read(dir.join(format!("{}.private.pem", name))).ok();
+            let key = Key::ec(&public, private.is_some())
+                .with_context(|| format!("loading key {}", name))?;
+            if name == active_name {
+                let private =
+                    private.ok_or_else(|| anyhow!("active key {} has no private key", name))?;
+                active = Some((private, keys.len()));
+            }
+            keys.push(key);
+        }
+
+        let (private, index) =
+            active.ok_or_else(|| anyhow!("active key {} not found in {:?}", active_name, dir))?;
+        Self::ec(&private, keys, index)
+    }
+
+    fn ec(private: &[u8], keys: Vec<Key>, active: usize) -> Result<Self> {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(keys[active].kid.clone());
+        Ok(Self {
+            header,
+            encoding_key: EncodingKey::from_ec_pem(private)?,
+            validation: Validation::new(Algorithm::ES256),
+            keys,
+        })
+    }
+
+    pub fn active_kid(&self) -> &str {
+        self.header.kid.as_deref().unwrap()
+    }
+
+    pub fn get(&self, kid: &str) -> Option<&Key> {
+        self.keys.iter().find(|k| k.kid == kid)
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.iter().filter_map(|k| k.jwk.clone()).collect(),
+        }
+    }
+
+    pub fn info(&self) -> Vec<KeyInfo> {
+        self.keys
+            .iter()
+            .map(|k| KeyInfo {
+                kid: k.kid.clone(),
+                active: k.kid == self.active_kid(),
+                can_sign: k.can_sign,
+            })
+            .collect()
+    }
+}
+
+/// Makes the key with id `kid` the active key of the key directory.
+/// The key needs a private half, the file is replaced atomically so other replicas
+/// reading the directory never see a partial write.
+pub(crate) fn promote(dir: &Path, kid: &str) -> Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        if let Some(name) = file_name
+            .to_str()
+            .and_then(|n| n.strip_suffix(".private.pem"))
+        {
+            names.push(name.to_string());
+        }
+    }
+
+    for name in names {
+        let public = match fs::read(dir.join(format!("{}.public.pem", name))) {
+            Ok(public) => public,
+            Err(_) => continue,
+        };
+        if ec_public_jwk(&public)?.common.key_id.as_deref() == Some(kid) {
+            let tmp = dir.join(format!("{}.tmp", ACTIVE_FILE));
+            fs::write(&tmp, &name)?;
+            fs::rename(&tmp, dir.join(ACTIVE_FILE))?;
+            return Ok(());
+        }
+    }
+    Err(anyhow!("no signing key with id {} in {:?}", kid, dir))
+}
+
+/// Builds the public JWK of a P-256 key from its SPKI PEM.
+/// The key id is the RFC 7638 thumbprint of the key.
+fn ec_public_jwk(pem: &[u8]) -> Result<Jwk> {
+    let body: String = std::str::from_utf8(pem)?
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = base64::decode(body)?;
+    // the SPKI ends with the uncompressed point 0x04 || x || y
+    if der.len() < 65 || der[der.len() - 65] != 0x04 {
+        return Err(anyhow!("public key is not an uncompressed P-256 point"));
+    }
+    let point = &der[der.len() - 64..];
+    let x = base64::encode_config(&point[..32], base64::URL_SAFE_NO_PAD);
+    let y = base64::encode_config(&point[32..], base64::URL_SAFE_NO_PAD);
+
+    sodiumoxide::init().unwrap();
+    let thumbprint_input = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+    let kid = base64::encode_config(
+        sha256::hash(thumbprint_input.as_bytes()).0,
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            algorithm: Some(Algorithm::ES256),
+            key_id: Some(kid),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+            key_type: EllipticCurveKeyType::EC,
+            curve: EllipticCurve::P256,
+            x,
+            y,
+        }),
+    })
+}
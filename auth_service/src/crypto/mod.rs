@@ -1,38 +1,74 @@
-use crate::config::Config;
-use crate::config::JwtSecret::{KeyPair, Pass};
+use crate::config::{Config, JwtSecret};
+use crate::crypto::keys::KeySet;
+use crate::metrics;
 use crate::mongo::Mongo;
-use crate::schema::{Role, UserClaims};
-use anyhow::{anyhow, Result};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use crate::schema::{ClientClaims, KeyInfo, RefreshToken, Role, UserClaims, UserWithHash};
+use crate::store::UserStore;
+use anyhow::{anyhow, bail, Result};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, encode, Algorithm};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::crypto::pwhash::argon2id13;
-use std::sync::Arc;
-use tracing::trace;
+use sodiumoxide::randombytes::randombytes;
+use std::ops::Add;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use time::{Duration, OffsetDateTime};
+use tracing::{info, trace};
+use uuid::Uuid;
+
+mod keys;
+pub mod totp;
+pub mod webauthn;
+
+const REFRESH_TOKEN_LIFETIME: Duration = Duration::days(30);
 
-#[derive(Clone)]
 pub struct JwtIssuer {
-    header: Header,
-    encoding_key: EncodingKey,
-    validation: Validation,
-    config: Config,
+    keys: RwLock<KeySet>,
+    jwt_config: JwtSecret,
 }
 
 impl JwtIssuer {
     pub async fn new(config: Config) -> Result<Self> {
-        let r = match &config.jwt_config {
-            Pass(p) => Self {
-                header: Header::new(Algorithm::HS256),
-                encoding_key: EncodingKey::from_secret(p.as_bytes()),
-                validation: Validation::default(),
-                config,
-            },
-            KeyPair { private, public: _ } => Self {
-                header: Header::new(Algorithm::ES256),
-                encoding_key: EncodingKey::from_ec_pem(private)?,
-                validation: Validation::new(Algorithm::ES256),
-                config,
-            },
-        };
-        Ok(r)
+        Ok(Self {
+            keys: RwLock::new(KeySet::load(&config.jwt_config)?),
+            jwt_config: config.jwt_config,
+        })
+    }
+
+    /// Public keys that can be used to verify issued tokens, ready to be served as JWKS.
+    pub fn jwks(&self) -> JwkSet {
+        self.keys.read().unwrap().jwks()
+    }
+
+    pub fn key_info(&self) -> Vec<KeyInfo> {
+        self.keys.read().unwrap().info()
+    }
+
+    /// Reloads all keys from the configured source.
+    /// Tokens signed by keys that are still present stay valid.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn reload(&self) -> Result<()> {
+        let keys = KeySet::load(&self.jwt_config)?;
+        let mut current = self.keys.write().unwrap();
+        if current.active_kid() != keys.active_kid() {
+            info!("now signing with key {}", keys.active_kid());
+        }
+        *current = keys;
+        Ok(())
+    }
+
+    /// Makes `kid` the signing key. The previous key is kept for verification.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn promote(&self, kid: &str) -> Result<()> {
+        match &self.jwt_config {
+            JwtSecret::KeyRing { dir } => keys::promote(Path::new(dir), kid)?,
+            _ => bail!("key rotation needs a key directory"),
+        }
+        self.reload()
     }
 
     #[tracing::instrument(level = "trace", skip(self, user))]
@@ -41,7 +77,55 @@ impl JwtIssuer {
         T: Into<UserClaims>,
     {
         let claim: UserClaims = user.into();
-        encode(&self.header, &claim, &self.encoding_key).map_err(Into::into)
+        let token = self.sign(&claim)?;
+        metrics::TOKENS_ISSUED.with_label_values(&["access"]).inc();
+        Ok(token)
+    }
+
+    /// Signs arbitrary claims with the active key.
+    #[tracing::instrument(level = "trace", skip(self, claims))]
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let keys = self.keys.read().unwrap();
+        encode(&keys.header, claims, &keys.encoding_key).map_err(Into::into)
+    }
+
+    /// Fails unless a key to sign with is loaded, for readiness probes.
+    pub fn check_keys(&self) -> Result<()> {
+        let keys = self.keys.read().unwrap();
+        match keys.get(keys.active_kid()) {
+            Some(key) if key.can_sign => Ok(()),
+            _ => bail!("no signing key loaded"),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.keys.read().unwrap().header.alg
+    }
+
+    /// Creates a new opaque refresh token for `user_id`.
+    /// Returns the token for the client and the record to store, which only contains its hash.
+    /// Passing a `family` continues a rotation chain, `None` starts a new one.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn issue_refresh(
+        &self,
+        user_id: &str,
+        family: Option<String>,
+        auth_time: i64,
+    ) -> (String, RefreshToken) {
+        let token = random_token();
+        metrics::TOKENS_ISSUED.with_label_values(&["refresh"]).inc();
+        let record = RefreshToken {
+            hash: hash_token(&token),
+            family: family.unwrap_or_else(|| Uuid::new_v4().to_string()),
+            user_id: user_id.to_string(),
+            exp: OffsetDateTime::now_utc()
+                .add(REFRESH_TOKEN_LIFETIME)
+                .unix_timestamp(),
+            used: false,
+            revoked: false,
+            auth_time,
+        };
+        (token, record)
     }
 
     // pub fn reissue(&self, jwt: &str) -> Result<String> {
@@ -50,44 +134,142 @@ impl JwtIssuer {
 
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn decode(&self, jwt: &str) -> Result<UserClaims> {
-        let header = jsonwebtoken::decode_header(jwt)?;
-        if header != self.header {
-            return Err(anyhow!("header does not match"));
-        }
-        match &self.config.jwt_config {
-            Pass(p) => decode::<UserClaims>(
-                jwt,
-                &DecodingKey::from_secret(p.as_bytes()),
-                &self.validation,
-            ),
-            KeyPair { private: _, public } => {
-                decode::<UserClaims>(jwt, &DecodingKey::from_ec_pem(public)?, &self.validation)
-            }
+        self.decode_claims(jwt)
+    }
+
+    /// Decodes a machine token issued with the client credentials grant.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn decode_client(&self, jwt: &str) -> Result<ClientClaims> {
+        self.decode_claims(jwt)
+    }
+
+    /// Decodes any claims signed with [`JwtIssuer::sign`].
+    pub fn decode_claims<T: DeserializeOwned>(&self, jwt: &str) -> Result<T> {
+        self.try_decode(jwt).map_err(|(_, e)| e)
+    }
+
+    /// Like [`JwtIssuer::decode_claims`], rejections also name the reason for the metrics.
+    fn try_decode<T: DeserializeOwned>(
+        &self,
+        jwt: &str,
+    ) -> std::result::Result<T, (&'static str, anyhow::Error)> {
+        let header = jsonwebtoken::decode_header(jwt).map_err(|e| ("malformed", e.into()))?;
+        let keys = self.keys.read().unwrap();
+        if header.alg != keys.header.alg {
+            return Err(("algorithm", anyhow!("algorithm does not match")));
         }
-        .map(|ts| ts.claims)
-        .map_err(Into::into)
+        // tokens issued before key ids were introduced carry no kid
+        let kid = header.kid.unwrap_or_else(|| keys.active_kid().to_string());
+        let key = keys
+            .get(&kid)
+            .ok_or_else(|| ("unknown_key", anyhow!("unknown key id {}", kid)))?;
+        decode::<T>(jwt, &key.decoding_key, &keys.validation)
+            .map(|ts| ts.claims)
+            .map_err(|e| {
+                let reason = match e.kind() {
+                    ErrorKind::ExpiredSignature => "expired",
+                    ErrorKind::ImmatureSignature => "not_yet_valid",
+                    ErrorKind::InvalidSignature => "signature",
+                    _ => "malformed",
+                };
+                (reason, e.into())
+            })
     }
 
-    #[tracing::instrument(level = "trace", skip(self, mongo))]
-    pub async fn validate_level(&self, mongo: &Arc<Mongo>, jwt: &str, level: Role) -> bool {
-        trace!("validating level {:?}", level);
-        if let Ok(claims) = self.decode(jwt).await {
-            trace!("succesfully decoded");
+    /// Decodes a first party user token and checks it against the current state of the user,
+    /// tokens of deleted users, revoked tokens and tokens older than the token version are rejected.
+    /// Access tokens issued to OIDC clients are rejected as well, see [`JwtIssuer::validate_oidc`].
+    pub async fn validate(
+        &self,
+        users: &Arc<dyn UserStore>,
+        mongo: &Arc<Mongo>,
+        jwt: &str,
+    ) -> Result<(UserClaims, UserWithHash)> {
+        self.validate_user_token(users, mongo, jwt, false).await
+    }
+
+    /// Like [`JwtIssuer::validate`], but also accepts access tokens issued to OIDC clients.
+    /// Only for userinfo and introspection, which respect the granted scopes.
+    pub async fn validate_oidc(
+        &self,
+        users: &Arc<dyn UserStore>,
+        mongo: &Arc<Mongo>,
+        jwt: &str,
+    ) -> Result<(UserClaims, UserWithHash)> {
+        self.validate_user_token(users, mongo, jwt, true).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, users, mongo))]
+    async fn validate_user_token(
+        &self,
+        users: &Arc<dyn UserStore>,
+        mongo: &Arc<Mongo>,
+        jwt: &str,
+        accept_oidc: bool,
+    ) -> Result<(UserClaims, UserWithHash)> {
+        let claims: UserClaims = self
+            .try_decode(jwt)
+            .map_err(|(reason, e)| validation_failure(reason, e))?;
+        trace!("succesfully decoded");
+        if claims.is_oidc() && !accept_oidc {
+            return Err(validation_failure(
+                "oidc_client",
+                anyhow!("token was issued to an oidc client"),
+            ));
+        }
+
+        let user = users
+            .require_user(&claims.user_id)
+            .await
+            .map_err(|e| validation_failure("unknown_user", e))?;
+
+        if claims.ver < user.token_version {
+            return Err(validation_failure(
+                "outdated",
+                anyhow!("token issued before version {}", user.token_version),
+            ));
+        }
 
-            let user = match mongo.get_user_from_id(&claims.user_id).await {
-                Ok(user) => user,
-                Err(_) => return false,
-            };
+        if mongo.is_token_revoked(&claims.jti).await? {
+            return Err(validation_failure("revoked", anyhow!("token revoked")));
+        }
+
+        Ok((claims, user))
+    }
 
-            return user.roles.contains(&level);
+    /// Validates `jwt` like [`JwtIssuer::validate`] and checks that its user holds `level`.
+    #[tracing::instrument(level = "trace", skip(self, users, mongo))]
+    pub async fn validate_level(
+        &self,
+        users: &Arc<dyn UserStore>,
+        mongo: &Arc<Mongo>,
+        jwt: &str,
+        level: Role,
+    ) -> Result<(UserClaims, UserWithHash)> {
+        trace!("validating level {:?}", level);
+        let (claims, user) = self.validate(users, mongo, jwt).await?;
+        if mongo.effective_roles(&user).await?.contains(&level) {
+            Ok((claims, user))
+        } else {
+            Err(anyhow!("missing role {:?}", level))
         }
-        false
     }
 }
 
+/// Counts a rejected user token under `reason`.
+fn validation_failure(reason: &'static str, err: anyhow::Error) -> anyhow::Error {
+    metrics::TOKEN_VALIDATION_FAILURES
+        .with_label_values(&[reason])
+        .inc();
+    err
+}
+
 #[tracing::instrument(level = "trace", skip(passwd))]
 pub fn hash(passwd: &str) -> argon2id13::HashedPassword {
     sodiumoxide::init().unwrap();
+    let _timer = metrics::PASSWORD_HASH_DURATION
+        .with_label_values(&["hash"])
+        .start_timer();
     argon2id13::pwhash(
         passwd.as_bytes(),
         argon2id13::OPSLIMIT_INTERACTIVE,
@@ -96,9 +278,30 @@ pub fn hash(passwd: &str) -> argon2id13::HashedPassword {
     .unwrap()
 }
 
+/// Creates a random url safe token, used for refresh tokens, codes and client secrets.
+pub fn random_token() -> String {
+    sodiumoxide::init().unwrap();
+    base64::encode_config(randombytes(32), base64::URL_SAFE_NO_PAD)
+}
+
+/// Computes the S256 PKCE challenge of a code verifier.
+pub fn pkce_challenge(verifier: &str) -> String {
+    hash_token(verifier)
+}
+
+/// Hashes an opaque token for storage and lookup.
+#[tracing::instrument(level = "trace", skip(token))]
+pub fn hash_token(token: &str) -> String {
+    sodiumoxide::init().unwrap();
+    base64::encode_config(sha256::hash(token.as_bytes()).0, base64::URL_SAFE_NO_PAD)
+}
+
 #[tracing::instrument(level = "trace", skip(hash, passwd))]
 pub fn verify(hash: [u8; 128], passwd: &str) -> bool {
     sodiumoxide::init().unwrap();
+    let _timer = metrics::PASSWORD_HASH_DURATION
+        .with_label_values(&["verify"])
+        .start_timer();
     match argon2id13::HashedPassword::from_slice(&hash) {
         Some(hp) => argon2id13::pwhash_verify(&hp, passwd.as_bytes()),
         _ => false,
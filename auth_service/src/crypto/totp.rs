@@ -0,0 +1,56 @@
+//! RFC 6238 time based one time passwords as used by authenticator apps.
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use sha1::Sha1;
+use sodiumoxide::randombytes::randombytes;
+
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+/// Number of steps a code may be off to allow for clock drift.
+const SKEW: i64 = 1;
+
+/// Generates a new base32 encoded secret.
+pub fn generate_secret() -> String {
+    sodiumoxide::init().unwrap();
+    BASE32_NOPAD.encode(&randombytes(20))
+}
+
+/// The `otpauth://` uri authenticator apps read from a qr code.
+pub fn provisioning_uri(secret: &str, issuer: &str, account: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        utf8_percent_encode(account, NON_ALPHANUMERIC),
+        secret,
+        issuer,
+        DIGITS,
+        STEP
+    )
+}
+
+/// Checks `code` against the steps around `now` and returns the matching step.
+/// Callers have to reject steps that were already used.
+pub fn verify(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: u32 = code.trim().parse().ok()?;
+    let current = now / STEP;
+    (current - SKEW..=current + SKEW).find(|&step| code_at(&key, step) == code)
+}
+
+/// HOTP value of `step` as described in RFC 4226.
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
@@ -0,0 +1,296 @@
+//! Verification of WebAuthn ceremonies.
+//! Only ES256 credentials and the `none` attestation are supported,
+//! which covers passkeys and current security keys.
+
+use crate::config::WebauthnConfig;
+use anyhow::{anyhow, bail, Result};
+use ciborium::value::Value;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use serde::Deserialize;
+use sodiumoxide::crypto::hash::sha256;
+
+pub const CREATE: &str = "webauthn.create";
+pub const GET: &str = "webauthn.get";
+/// COSE algorithm id of ES256.
+pub const ES256: i64 = -7;
+
+pub const FLAG_USER_PRESENT: u8 = 0x01;
+pub const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Deserialize, Debug)]
+pub struct ClientData {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub challenge: String,
+    pub origin: String,
+}
+
+/// The parts of the authenticator data the server needs.
+#[derive(Debug)]
+pub struct AuthenticatorData {
+    pub sign_count: u32,
+    /// Credential id and uncompressed public key of a new credential.
+    pub credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// A credential created by a registration ceremony.
+#[derive(Debug)]
+pub struct NewCredential {
+    pub id: Vec<u8>,
+    /// Uncompressed P-256 point.
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// Decodes base64url with or without padding, as sent by browsers.
+pub fn decode_b64(value: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(
+        value.trim_end_matches('='),
+        base64::URL_SAFE_NO_PAD,
+    )?)
+}
+
+pub fn encode_b64(value: &[u8]) -> String {
+    base64::encode_config(value, base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks the client data of a ceremony and returns the challenge it answers.
+pub fn check_client_data(
+    config: &WebauthnConfig,
+    client_data_json: &[u8],
+    kind: &str,
+) -> Result<String> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)?;
+    if client_data.kind != kind {
+        bail!("unexpected ceremony {}", client_data.kind);
+    }
+    if client_data.origin != config.origin {
+        bail!("unexpected origin {}", client_data.origin);
+    }
+    Ok(client_data.challenge)
+}
+
+/// Parses authenticator data and checks it belongs to this relying party.
+pub fn parse_authenticator_data(config: &WebauthnConfig, data: &[u8]) -> Result<AuthenticatorData> {
+    if data.len() < 37 {
+        bail!("authenticator data too short");
+    }
+    if data[..32] != sha256::hash(config.rp_id.as_bytes()).0 {
+        bail!("authenticator data for another relying party");
+    }
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        bail!("user not present");
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        // aaguid (16 bytes), credential id length (2 bytes), credential id, COSE key
+        let rest = data
+            .get(37 + 16..)
+            .ok_or_else(|| anyhow!("missing credential data"))?;
+        if rest.len() < 2 {
+            bail!("missing credential id");
+        }
+        let id_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let id = rest
+            .get(2..2 + id_len)
+            .ok_or_else(|| anyhow!("credential id too short"))?
+            .to_vec();
+        let key: Value = ciborium::de::from_reader(&rest[2 + id_len..])?;
+        Some((id, cose_es256_key(&key)?))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        sign_count,
+        credential,
+    })
+}
+
+/// Extracts the authenticator data of an attestation object,
+/// the attestation statement is not checked as only `none` is requested.
+pub fn attestation_auth_data(attestation_object: &[u8]) -> Result<Vec<u8>> {
+    let value: Value = ciborium::de::from_reader(attestation_object)?;
+    map_get(&value, &Value::Text("authData".into()))
+        .and_then(|v| v.as_bytes())
+        .cloned()
+        .ok_or_else(|| anyhow!("attestation object without authData"))
+}
+
+/// Checks the attestation object of a registration and returns the new credential.
+pub fn verify_registration(
+    config: &WebauthnConfig,
+    attestation_object: &[u8],
+) -> Result<NewCredential> {
+    let auth_data = parse_authenticator_data(config, &attestation_auth_data(attestation_object)?)?;
+    let (id, public_key) = auth_data
+        .credential
+        .ok_or_else(|| anyhow!("attestation without credential"))?;
+    Ok(NewCredential {
+        id,
+        public_key,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Checks an assertion of a credential whose counter was last `stored_count` and returns the new counter.
+/// A counter that did not increase means the credential may be cloned,
+/// authenticators without a counter always send 0.
+pub fn verify_login(
+    config: &WebauthnConfig,
+    public_key: &[u8],
+    stored_count: u32,
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<u32> {
+    let auth_data = parse_authenticator_data(config, authenticator_data)?;
+    verify_assertion(public_key, authenticator_data, client_data_json, signature)?;
+    if (auth_data.sign_count != 0 || stored_count != 0) && auth_data.sign_count <= stored_count {
+        bail!(
+            "signature counter went back from {} to {}, the credential may be cloned",
+            stored_count,
+            auth_data.sign_count
+        );
+    }
+    Ok(auth_data.sign_count)
+}
+
+/// Checks an assertion signature over the authenticator data and the client data hash.
+pub fn verify_assertion(
+    public_key: &[u8],
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&sha256::hash(client_data_json).0);
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+        .verify(&message, signature)
+        .map_err(|_| anyhow!("invalid signature"))
+}
+
+/// Converts a COSE EC2 P-256 key into an uncompressed point.
+fn cose_es256_key(key: &Value) -> Result<Vec<u8>> {
+    let int = |label: i64| map_get(key, &Value::Integer(label.into()));
+    let is = |value: Option<&Value>, expected: i64| {
+        value
+            .and_then(|v| v.as_integer())
+            .is_some_and(|v| v == expected.into())
+    };
+    // kty EC2, alg ES256, crv P-256
+    if !is(int(1), 2) || !is(int(3), ES256) || !is(int(-1), 1) {
+        bail!("only ES256 credentials are supported");
+    }
+    let coordinate = |label: i64| {
+        int(label)
+            .and_then(|v| v.as_bytes())
+            .filter(|b| b.len() == 32)
+            .ok_or_else(|| anyhow!("invalid key coordinate"))
+    };
+    let mut point = vec![0x04];
+    point.extend_from_slice(coordinate(-2)?);
+    point.extend_from_slice(coordinate(-3)?);
+    Ok(point)
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+    map.as_map()?.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::SoftAuthenticator;
+
+    fn config() -> WebauthnConfig {
+        WebauthnConfig {
+            rp_id: "board.test".into(),
+            rp_name: "board".into(),
+            origin: "https://board.test".into(),
+        }
+    }
+
+    /// Registers the authenticator like the register_finish handler and returns the stored key and counter.
+    fn register(authenticator: &SoftAuthenticator, sign_count: u32) -> NewCredential {
+        let client_data = SoftAuthenticator::client_data(CREATE, "registration", &config().origin);
+        assert_eq!(
+            check_client_data(&config(), &client_data, CREATE).unwrap(),
+            "registration"
+        );
+        verify_registration(&config(), &authenticator.attestation_object(sign_count)).unwrap()
+    }
+
+    /// Signs in like the login_finish handler and returns the new counter.
+    fn login(
+        authenticator: &SoftAuthenticator,
+        credential: &NewCredential,
+        stored_count: u32,
+        sign_count: u32,
+    ) -> Result<u32> {
+        let client_data = SoftAuthenticator::client_data(GET, "login", &config().origin);
+        assert_eq!(check_client_data(&config(), &client_data, GET)?, "login");
+        let (auth_data, signature) = authenticator.assert(&client_data, sign_count);
+        verify_login(
+            &config(),
+            &credential.public_key,
+            stored_count,
+            &auth_data,
+            &client_data,
+            &signature,
+        )
+    }
+
+    #[test]
+    fn registers_and_signs_in_with_a_software_authenticator() {
+        let authenticator = SoftAuthenticator::new("board.test");
+        let credential = register(&authenticator, 0);
+        assert_eq!(credential.id, authenticator.credential_id);
+        assert_eq!(credential.public_key, authenticator.public_key());
+        assert_eq!(credential.sign_count, 0);
+
+        assert_eq!(login(&authenticator, &credential, 0, 1).unwrap(), 1);
+        assert_eq!(login(&authenticator, &credential, 1, 5).unwrap(), 5);
+    }
+
+    #[test]
+    fn rejects_a_counter_that_does_not_increase() {
+        let authenticator = SoftAuthenticator::new("board.test");
+        let credential = register(&authenticator, 3);
+        assert_eq!(login(&authenticator, &credential, 3, 4).unwrap(), 4);
+
+        assert!(login(&authenticator, &credential, 4, 4).is_err());
+        assert!(login(&authenticator, &credential, 4, 2).is_err());
+        assert!(login(&authenticator, &credential, 4, 0).is_err());
+    }
+
+    #[test]
+    fn accepts_authenticators_without_a_counter() {
+        let authenticator = SoftAuthenticator::new("board.test");
+        let credential = register(&authenticator, 0);
+        assert_eq!(login(&authenticator, &credential, 0, 0).unwrap(), 0);
+        assert_eq!(login(&authenticator, &credential, 0, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_signatures_of_another_key() {
+        let authenticator = SoftAuthenticator::new("board.test");
+        let credential = register(&authenticator, 0);
+        let other = SoftAuthenticator::new("board.test");
+        assert!(login(&other, &credential, 0, 1).is_err());
+    }
+
+    #[test]
+    fn rejects_other_relying_parties_and_origins() {
+        let authenticator = SoftAuthenticator::new("evil.test");
+        assert!(verify_registration(&config(), &authenticator.attestation_object(0)).is_err());
+
+        let client_data =
+            SoftAuthenticator::client_data(CREATE, "registration", "https://evil.test");
+        assert!(check_client_data(&config(), &client_data, CREATE).is_err());
+        let client_data = SoftAuthenticator::client_data(GET, "registration", &config().origin);
+        assert!(check_client_data(&config(), &client_data, CREATE).is_err());
+    }
+}
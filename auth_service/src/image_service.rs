@@ -1,3 +1,4 @@
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Result;
 use serde::Deserialize;
@@ -5,6 +6,7 @@ use serde::Serialize;
 use tracing::warn;
 
 use crate::config::ImageServiceConfig;
+use crate::metrics;
 
 #[derive(Debug, Clone)]
 pub struct ImageService {
@@ -18,6 +20,21 @@ impl ImageService {
         }
     }
 
+    /// Checks that the image service answers. It has no health route, any response that is not a server error counts.
+    #[tracing::instrument(name = "ping_image_service")]
+    pub async fn ping(&self) -> Result<()> {
+        let client = awc::Client::default();
+        let response = client
+            .get(format!("http://{}/", self.url))
+            .send()
+            .await
+            .map_err(|err| anyhow!("image_service unreachable: {}", err))?;
+        if response.status().is_server_error() {
+            bail!("image_service answered {}", response.status());
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(name = "upload_image")]
     pub async fn upload_image(&self, image_data: String) -> Result<String> {
         #[derive(Debug, Serialize, Deserialize)]
@@ -39,6 +56,7 @@ impl ImageService {
             Ok(r) => r,
             Err(err) => {
                 warn!("{:?}", err);
+                metrics::IMAGE_SERVICE_UPLOADS.with_label_values(&["unreachable"]).inc();
                 bail!("Error sending request to image_service")
             }
         };
@@ -47,10 +65,12 @@ impl ImageService {
             Ok(r) => r,
             Err(err) => {
                 warn!("{:?}", err);
+                metrics::IMAGE_SERVICE_UPLOADS.with_label_values(&["invalid_response"]).inc();
                 bail!("Error decoding response image_service")
             }
         }.id;
 
+        metrics::IMAGE_SERVICE_UPLOADS.with_label_values(&["success"]).inc();
         Ok(id)
     }
 }
@@ -0,0 +1,115 @@
+use anyhow::bail;
+use anyhow::Result;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::{info, warn};
+
+use crate::config::SmtpConfig;
+
+#[derive(Clone)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl Mailer {
+    pub fn new(config: SmtpConfig) -> Result<Self> {
+        let mut builder = if config.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        } else {
+            // plain connection, e.g. to the local relay or a smtp sink
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+        }
+        .port(config.port);
+
+        if let (Some(user), Some(password)) = (config.user, config.password) {
+            builder = builder.credentials(Credentials::new(user, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from,
+        })
+    }
+
+    #[tracing::instrument(name = "send_mail", skip(self, body))]
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.parse()?)
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+
+        match self.transport.send(message).await {
+            Ok(_) => {
+                info!("sent mail to {}", to);
+                Ok(())
+            }
+            Err(err) => {
+                warn!("{:?}", err);
+                bail!("Error sending mail")
+            }
+        }
+    }
+
+    pub async fn send_verification(&self, to: &str, name: &str, link: &str) -> Result<()> {
+        self.send(
+            to,
+            "Verify your email address",
+            format!(
+                "Hello {},\n\nplease confirm your email address by opening the following link:\n{}\n",
+                name, link
+            ),
+        )
+        .await
+    }
+
+    pub async fn send_password_reset(&self, to: &str, name: &str, link: &str) -> Result<()> {
+        self.send(
+            to,
+            "Reset your password",
+            format!(
+                "Hello {},\n\na password reset was requested for your account. \
+                 Open the following link to choose a new password:\n{}\n\n\
+                 If you did not request this, you can ignore this mail.\n",
+                name, link
+            ),
+        )
+        .await
+    }
+
+    pub async fn send_email_change(&self, to: &str, name: &str, link: &str) -> Result<()> {
+        self.send(
+            to,
+            "Confirm your new email address",
+            format!(
+                "Hello {},\n\nplease confirm the new email address of your account \
+                 by opening the following link:\n{}\n",
+                name, link
+            ),
+        )
+        .await
+    }
+
+    pub async fn send_email_changed(
+        &self,
+        to: &str,
+        name: &str,
+        new_email: &str,
+        link: &str,
+    ) -> Result<()> {
+        self.send(
+            to,
+            "Your email address was changed",
+            format!(
+                "Hello {},\n\nthe email address of your account was changed to {}.\n\n\
+                 If you did not do this, open the following link to restore this address \
+                 and sign out all sessions:\n{}\n",
+                name, new_email, link
+            ),
+        )
+        .await
+    }
+}
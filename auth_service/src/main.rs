@@ -1,15 +1,18 @@
-use crate::config::Config;
+use crate::config::{Config, JwtSecret, RateLimitBackend};
 use crate::crypto::JwtIssuer;
 use crate::api::middleware;
+use crate::api::middleware::{MemoryRateLimitStore, RateKey, RateLimit, RateLimitStore};
 use crate::image_service::ImageService;
+use crate::mail::Mailer;
+use crate::password_policy::PasswordPolicy;
+use crate::tls::CertResolver;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
-use tracing_subscriber::util::SubscriberInitExt;
 use std::sync::Arc;
-use tracing::info;
+use std::time::Duration;
+use tracing::{info, warn};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use actix_cors::Cors;
-use tracing_subscriber::layer::SubscriberExt;
 use opentelemetry::global;
 use actix_web_opentelemetry::RequestTracing;
 use tracing_actix_web::TracingLogger;
@@ -20,44 +23,170 @@ mod crypto;
 mod mongo;
 mod schema;
 mod image_service;
+mod mail;
+mod metrics;
+mod password_policy;
+mod store;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod tls;
+
+const KEY_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// How often renewed TLS certificates are picked up.
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(3600);
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
+    let config = Config::load()?;
 
-    global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
-    let tracer = opentelemetry_jaeger::new_pipeline()
-        .with_service_name("auth-service")
-        .with_agent_endpoint("tracing:6831")
-        .install_simple()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--print-config") {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
+    }
 
-    let opentelemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-    tracing_subscriber::registry()
-        .with(opentelemetry)
-        .try_init()?;
+    telemetry::init(&config.tracing)?;
 
-    let config = Config::from_env()?;
+    if args.first().map(String::as_str) == Some("migrate") {
+        return migrate(&config, args.iter().any(|a| a == "--dry-run")).await;
+    }
 
-    let mongo = Arc::new(mongo::Mongo::from_config(config.clone()).await?);
+    let mongo = Arc::new(mongo::Mongo::from_config(&config).await?);
+    let users = store::from_config(&config.user_store, mongo.database())?;
+    store::ensure_default_user(users.as_ref(), &config.default_user).await?;
 
     let jwt_issuer = Arc::new(JwtIssuer::new(config.clone()).await?);
 
+    if let JwtSecret::KeyRing { dir } = &config.jwt_config {
+        // pick up keys added or promoted by other replicas
+        info!("watching key directory {}", dir);
+        let jwt_issuer = jwt_issuer.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(KEY_RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = jwt_issuer.reload() {
+                    warn!("reloading keys failed: {:?}", e);
+                }
+            }
+        });
+    }
+
     let image_service = ImageService::new(config.image_service.clone());
 
-    info!("starting auth_service on port 8080");
+    let mailer = Mailer::new(config.smtp.clone())?;
+
+    let password_policy = Arc::new(PasswordPolicy::new(config.password_policy.clone())?);
+
+    let rate_limit_store: Arc<dyn RateLimitStore> = match config.rate_limit.backend {
+        RateLimitBackend::Memory => Arc::new(MemoryRateLimitStore::default()),
+        RateLimitBackend::Mongo => mongo.clone(),
+    };
+
+    let server_config = config.server.clone();
+    let cert_resolver = match &server_config.tls {
+        Some(tls) => Some(Arc::new(CertResolver::new(tls.clone())?)),
+        None => None,
+    };
+
+    if let Some(cert_resolver) = cert_resolver.clone() {
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(CERT_RELOAD_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = cert_resolver.reload() {
+                    warn!("reloading tls certificate failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    info!(
+        "starting auth_service on {}:{} ({})",
+        server_config.address,
+        server_config.port,
+        if cert_resolver.is_some() { "https" } else { "http" }
+    );
+
+    let body_limit = server_config.body_limit;
+    let mut server = HttpServer::new(move || {
+        let limits = &config.rate_limit;
+        let rate_limit =
+            |name, limit, key| RateLimit::new(name, limit, key, rate_limit_store.clone());
 
-    HttpServer::new(move || {
         App::new()
             .app_data(Data::new(mongo.clone()))
+            .app_data(Data::new(users.clone()))
             .app_data(Data::new(jwt_issuer.clone()))
             .app_data(Data::new(image_service.clone()))
+            .app_data(Data::new(mailer.clone()))
+            .app_data(Data::new(password_policy.clone()))
+            .wrap(metrics::RequestMetrics)
             .wrap(RequestTracing::new())
             .wrap(actix_web::middleware::NormalizePath::default())
             .wrap(Cors::permissive())
+            .app_data(Data::new(config.clone()))
+            .app_data(web::PayloadConfig::new(body_limit))
+            .app_data(web::JsonConfig::default().limit(body_limit))
+            .app_data(web::FormConfig::default().limit(body_limit))
+            .route("/metrics", web::get().to(metrics::metrics))
+            .route("/health/live", web::get().to(api::health::HealthApi::live))
+            .route("/health/ready", web::get().to(api::health::HealthApi::ready))
+            .route("/.well-known/jwks.json", web::get().to(api::jwks))
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(api::oidc::OidcApi::discovery),
+            )
             .service(
                 web::scope("/auth")
-                    .route("/signin", web::post().to(api::Auth::sign_in))
-                    .route("/signup", web::post().to(api::Auth::sign_up))
-                    .route("/reissue", web::post().to(api::Auth::reissue))
+                    .service(
+                        web::resource("/signin")
+                            .wrap(rate_limit("signin", limits.signin, RateKey::Ip))
+                            .route(web::post().to(api::Auth::sign_in)),
+                    )
+                    .service(
+                        web::resource("/signup")
+                            .wrap(rate_limit("signup", limits.signup, RateKey::Ip))
+                            .route(web::post().to(api::Auth::sign_up)),
+                    )
+                    .route("/verify_email", web::get().to(api::Auth::verify_email))
+                    .service(
+                        web::resource("/verify_email/resend")
+                            .wrap(rate_limit("mail", limits.mail, RateKey::Ip))
+                            .route(web::post().to(api::Auth::resend_verification)),
+                    )
+                    .service(
+                        web::resource("/password/forgot")
+                            .wrap(rate_limit("mail", limits.mail, RateKey::Ip))
+                            .route(web::post().to(api::PasswordApi::forgot)),
+                    )
+                    .route("/password/reset", web::post().to(api::PasswordApi::reset))
+                    .route("/email/confirm", web::get().to(api::EmailApi::confirm))
+                    .route("/email/revert", web::get().to(api::EmailApi::revert))
+                    .service(
+                        web::resource("/signin/2fa")
+                            .wrap(rate_limit("signin", limits.signin, RateKey::Ip))
+                            .route(web::post().to(api::two_factor::TwoFactorApi::sign_in)),
+                    )
+                    .route(
+                        "/webauthn/login/start",
+                        web::post().to(api::webauthn::WebauthnApi::login_start),
+                    )
+                    .route(
+                        "/webauthn/login/finish",
+                        web::post().to(api::webauthn::WebauthnApi::login_finish),
+                    )
+                    .service(
+                        web::resource("/reissue")
+                            .wrap(rate_limit("reissue", limits.reissue, RateKey::User))
+                            .route(web::post().to(api::Auth::reissue)),
+                    )
+                    .route("/refresh", web::post().to(api::Auth::refresh))
+                    .route("/token", web::post().to(api::oidc::OidcApi::token))
+                    .route("/introspect", web::post().to(api::oidc::OidcApi::introspect))
+                    .route("/forward", web::route().to(api::Auth::forward))
                     .service(
                         web::scope("/admin")
                             .wrap(HttpAuthentication::bearer(middleware::validate_admin))
@@ -67,27 +196,144 @@ async fn main() -> anyhow::Result<()> {
                             .route(
                                 "/delete_user/{id}",
                                 web::delete().to(api::AdminApi::delete_user),
+                            )
+                            .route("/unlock", web::post().to(api::AdminApi::unlock))
+                            .route("/keys", web::get().to(api::KeyApi::list_keys))
+                            .route("/keys/reload", web::post().to(api::KeyApi::reload_keys))
+                            .route(
+                                "/keys/promote/{kid}",
+                                web::post().to(api::KeyApi::promote_key),
+                            )
+                            .route(
+                                "/2fa/policy",
+                                web::get().to(api::two_factor::TwoFactorApi::get_policy),
+                            )
+                            .route(
+                                "/2fa/policy",
+                                web::post().to(api::two_factor::TwoFactorApi::set_policy),
+                            )
+                            .route(
+                                "/2fa/{id}",
+                                web::delete().to(api::two_factor::TwoFactorApi::reset),
+                            )
+                            .route("/clients", web::get().to(api::oidc::OidcApi::list_clients))
+                            .route(
+                                "/clients",
+                                web::post().to(api::oidc::OidcApi::register_client),
+                            )
+                            .route(
+                                "/clients/{id}",
+                                web::delete().to(api::oidc::OidcApi::delete_client),
                             ),
                     )
+                    .route("/oidc/authorize", web::get().to(api::oidc::OidcApi::authorize))
+                    .service(
+                        // registered before the oidc scope, the only route taking tokens of oidc clients
+                        web::resource("/oidc/userinfo")
+                            .wrap(HttpAuthentication::bearer(middleware::validate_oidc_user))
+                            .route(web::get().to(api::oidc::OidcApi::userinfo))
+                            .route(web::post().to(api::oidc::OidcApi::userinfo)),
+                    )
+                    .service(
+                        web::scope("/oidc")
+                            .wrap(HttpAuthentication::bearer(middleware::validate_user))
+                            .route("/authorize", web::post().to(api::oidc::OidcApi::confirm)),
+                    )
+                    .service(
+                        // registered before the user scope, service clients may call it too
+                        web::resource("/user/get_batch")
+                            .wrap(HttpAuthentication::bearer(middleware::validate_user_or_client))
+                            .wrap(rate_limit("get_batch", limits.get_batch, RateKey::User))
+                            .route(web::post().to(api::UserApi::get_batch)),
+                    )
                     .service(
                         web::scope("/user")
                             .wrap(HttpAuthentication::bearer(middleware::validate_user))
                             .route("/info", web::get().to(api::UserApi::info))
                             .route("/update", web::post().to(api::UserApi::update))
                             .route("/delete", web::delete().to(api::UserApi::delete))
-                            .route("/get_batch", web::post().to(api::UserApi::get_batch))
+                            .route("/logout", web::post().to(api::UserApi::logout))
+                            .route("/logout_all", web::post().to(api::UserApi::logout_all))
+                            .route(
+                                "/2fa/enroll",
+                                web::post().to(api::two_factor::TwoFactorApi::enroll),
+                            )
+                            .route(
+                                "/2fa/confirm",
+                                web::post().to(api::two_factor::TwoFactorApi::confirm),
+                            )
+                            .route(
+                                "/2fa/recovery_codes",
+                                web::post().to(api::two_factor::TwoFactorApi::regenerate_recovery_codes),
+                            )
+                            .route(
+                                "/2fa/disable",
+                                web::post().to(api::two_factor::TwoFactorApi::disable),
+                            )
+                            .route(
+                                "/webauthn/register/start",
+                                web::post().to(api::webauthn::WebauthnApi::register_start),
+                            )
+                            .route(
+                                "/webauthn/register/finish",
+                                web::post().to(api::webauthn::WebauthnApi::register_finish),
+                            )
+                            .route(
+                                "/webauthn/credentials",
+                                web::get().to(api::webauthn::WebauthnApi::list_credentials),
+                            )
+                            .route(
+                                "/webauthn/credentials/{id}",
+                                web::delete().to(api::webauthn::WebauthnApi::delete_credential),
+                            )
                             .route("/{id}", web::get().to(api::UserApi::get))
-                            .route("/email/{email}", web::get().to(api::UserApi::get_email)),
+                            .service(
+                                web::resource("/email/{email}")
+                                    .wrap(rate_limit("user_email", limits.user_email, RateKey::User))
+                                    .route(web::get().to(api::UserApi::get_email)),
+                            ),
                     )
                     .route("/version", web::get().to(api::version))
             )
             
     })
-    .bind(("0.0.0.0", 8080))?
-    .run()
+    .keep_alive(Duration::from_secs(server_config.keep_alive));
+
+    if let Some(workers) = server_config.workers {
+        server = server.workers(workers);
+    }
+    let address = (server_config.address.as_str(), server_config.port);
+    let server = match cert_resolver {
+        Some(cert_resolver) => server.bind_rustls_021(address, cert_resolver.server_config())?,
+        None => server.bind(address)?,
+    };
+
+    server.run()
     .await
     .map_err(anyhow::Error::from)?;
 
     global::shutdown_tracer_provider();
     Ok(())
 }
+
+/// `auth_service migrate [--dry-run]`, applies or lists the pending migrations and exits.
+async fn migrate(config: &Config, dry_run: bool) -> anyhow::Result<()> {
+    if !config.user_store.is_mongo() {
+        println!("users are not kept in mongo, nothing to migrate");
+        return Ok(());
+    }
+    let mongo = mongo::Mongo::open(config).await?;
+    mongo.connect().await?;
+    let results = mongo.migrate(dry_run).await?;
+    if results.is_empty() {
+        println!("no pending migrations");
+    }
+    for result in results {
+        let verb = if dry_run { "would change" } else { "changed" };
+        println!(
+            "{} ({}): {} {} documents",
+            result.id, result.description, verb, result.changed
+        );
+    }
+    Ok(())
+}
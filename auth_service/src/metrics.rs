@@ -0,0 +1,169 @@
+//! Prometheus metrics, served in the text format at `/metrics`.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::time::Instant;
+use tracing::warn;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Handled requests by route and status.",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time to handle a request by route.",
+        &["method", "route"]
+    )
+    .unwrap();
+    pub static ref SIGN_INS: IntCounterVec = register_int_counter_vec!(
+        "auth_sign_ins_total",
+        "Password sign in attempts by result.",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref SIGN_UPS: IntCounterVec =
+        register_int_counter_vec!("auth_sign_ups_total", "Sign ups by result.", &["result"])
+            .unwrap();
+    pub static ref TOKENS_ISSUED: IntCounterVec = register_int_counter_vec!(
+        "auth_tokens_issued_total",
+        "Issued tokens by kind.",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref TOKEN_VALIDATION_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "auth_token_validation_failures_total",
+        "Rejected user tokens by reason.",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref LOCKOUTS: IntCounterVec = register_int_counter_vec!(
+        "auth_lockouts_total",
+        "Sign in lockouts by the key that reached its threshold.",
+        &["scope"]
+    )
+    .unwrap();
+    pub static ref PASSWORD_HASH_DURATION: HistogramVec = register_histogram_vec!(
+        "auth_password_hash_duration_seconds",
+        "Time spent in argon2 by operation.",
+        &["operation"]
+    )
+    .unwrap();
+    static ref MONGO_COMMAND_DURATION: HistogramVec = register_histogram_vec!(
+        "auth_mongo_command_duration_seconds",
+        "Time of mongo commands by command and result.",
+        &["command", "result"]
+    )
+    .unwrap();
+    pub static ref IMAGE_SERVICE_UPLOADS: IntCounterVec = register_int_counter_vec!(
+        "auth_image_service_uploads_total",
+        "Uploads to the image service by result.",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Serves all registered metrics.
+pub async fn metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(e) => {
+            warn!("encoding metrics failed: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Records the duration of every command sent to mongo, registered on the client in [`crate::mongo::Mongo::connect`].
+pub struct MongoCommandMetrics;
+
+impl CommandEventHandler for MongoCommandMetrics {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        MONGO_COMMAND_DURATION
+            .with_label_values(&[&event.command_name, "success"])
+            .observe(event.duration.as_secs_f64());
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        MONGO_COMMAND_DURATION
+            .with_label_values(&[&event.command_name, "failure"])
+            .observe(event.duration.as_secs_f64());
+    }
+}
+
+/// Counts and times requests per route pattern, requests matching no route share the route `unmatched`.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let method = req.method().to_string();
+        // the pattern keeps ids out of the labels
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let result = service.call(req).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[&method, &route])
+                .observe(start.elapsed().as_secs_f64());
+            HTTP_REQUESTS
+                .with_label_values(&[&method, &route, status.as_str()])
+                .inc();
+            result
+        })
+    }
+}
@@ -0,0 +1,281 @@
+use super::{is_duplicate_key, Mongo};
+use crate::schema::{normalize_email, AccountStatus, MigrationRecord};
+use anyhow::{bail, Context, Result};
+use futures_util::future::{select, BoxFuture, Either};
+use futures_util::stream::StreamExt;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::{IndexOptions, UpdateOptions};
+use mongodb::{Database, IndexModel};
+use std::time::Duration;
+use tracing::info;
+use uuid::Uuid;
+
+/// Id of the lock document in `_migrations`.
+const LOCK_ID: &str = "lock";
+/// Time after which the lock of a crashed replica can be taken over.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+/// The holder extends the lock this often while it migrates.
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Runs a migration, or only counts the documents it would change if the flag is set.
+/// Returns the number of changed documents.
+type MigrationFn = for<'a> fn(&'a Database, bool) -> BoxFuture<'a, Result<u64>>;
+
+struct Migration {
+    id: &'static str,
+    description: &'static str,
+    run: MigrationFn,
+}
+
+/// Every migration in the order it is applied.
+/// Migrations have to be idempotent, a run interrupted before it was recorded is repeated.
+/// Applied entries must never be changed or reordered, add new ones at the end.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        id: "0001_user_defaults",
+        description: "store defaults of fields added to users",
+        run: user_defaults,
+    },
+    Migration {
+        id: "0002_normalize_emails",
+        description: "add email_normalized to users",
+        run: normalize_emails,
+    },
+    Migration {
+        id: "0003_user_indexes",
+        description: "unique indexes on id and email_normalized of users",
+        run: user_indexes,
+    },
+];
+
+/// Outcome of a migration, `changed` counts the documents that were or would be changed.
+#[derive(Debug)]
+pub struct MigrationResult {
+    pub id: &'static str,
+    pub description: &'static str,
+    pub changed: u64,
+}
+
+impl Mongo {
+    /// Ids of the migrations that were not applied yet.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn pending_migrations(&self) -> Result<Vec<&'static str>> {
+        Ok(self.pending().await?.iter().map(|m| m.id).collect())
+    }
+
+    /// Applies all pending migrations in order and records them in `_migrations`.
+    /// Only one replica migrates at a time, the others wait for the lock.
+    /// A `dry_run` only reports what each pending migration would change.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn migrate(&self, dry_run: bool) -> Result<Vec<MigrationResult>> {
+        if dry_run {
+            let mut results = Vec::new();
+            for migration in self.pending().await? {
+                results.push(MigrationResult {
+                    id: migration.id,
+                    description: migration.description,
+                    changed: (migration.run)(&self.database, true).await?,
+                });
+            }
+            return Ok(results);
+        }
+
+        let owner = Uuid::new_v4().to_string();
+        self.lock_migrations(&owner).await?;
+        let apply = self.apply_pending();
+        let keep = self.keep_migration_lock(&owner);
+        futures_util::pin_mut!(apply, keep);
+        // a migration may take longer than the timeout, it is aborted if the lock is lost
+        let results = match select(apply, keep).await {
+            Either::Left((results, _)) => results,
+            Either::Right((lost, _)) => Err(lost),
+        };
+        self.migrations
+            .delete_one(doc! {"_id": LOCK_ID, "owner": &owner}, None)
+            .await?;
+        results
+    }
+
+    async fn apply_pending(&self) -> Result<Vec<MigrationResult>> {
+        // pending is read again under the lock, another replica may have migrated meanwhile
+        let mut results = Vec::new();
+        for migration in self.pending().await? {
+            info!(
+                "applying migration {}: {}",
+                migration.id, migration.description
+            );
+            let changed = (migration.run)(&self.database, false)
+                .await
+                .with_context(|| format!("migration {} failed", migration.id))?;
+            self.migrations
+                .clone_with_type::<MigrationRecord>()
+                .insert_one(
+                    MigrationRecord {
+                        id: migration.id.to_string(),
+                        description: migration.description.to_string(),
+                        applied_at: DateTime::now(),
+                        changed,
+                    },
+                    None,
+                )
+                .await?;
+            info!("migration {} changed {} documents", migration.id, changed);
+            results.push(MigrationResult {
+                id: migration.id,
+                description: migration.description,
+                changed,
+            });
+        }
+        Ok(results)
+    }
+
+    async fn pending(&self) -> Result<Vec<&'static Migration>> {
+        let mut applied = Vec::new();
+        let mut records = self
+            .migrations
+            .clone_with_type::<MigrationRecord>()
+            .find(doc! {"applied_at": {"$exists": true}}, None)
+            .await?;
+        while let Some(record) = records.next().await {
+            applied.push(record?.id);
+        }
+        Ok(MIGRATIONS
+            .iter()
+            .filter(|m| !applied.iter().any(|id| id == m.id))
+            .collect())
+    }
+
+    /// Waits until the lock document is free or expired and takes it.
+    async fn lock_migrations(&self, owner: &str) -> Result<()> {
+        loop {
+            let now = DateTime::now();
+            let expires_at =
+                DateTime::from_millis(now.timestamp_millis() + LOCK_TIMEOUT.as_millis() as i64);
+            // a held lock does not match the filter, the upsert then fails on the duplicate id
+            let result = self
+                .migrations
+                .update_one(
+                    doc! {"_id": LOCK_ID, "expires_at": {"$lt": now}},
+                    doc! {"$set": {"owner": owner, "expires_at": expires_at}},
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await
+                .map_err(anyhow::Error::from);
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) if is_duplicate_key(&e) => {
+                    info!("waiting for another replica to finish migrating");
+                    actix_web::rt::time::sleep(LOCK_POLL_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Extends the lock held by `owner`, fails if another replica took it over.
+    async fn renew_migration_lock(&self, owner: &str) -> Result<()> {
+        let expires_at = DateTime::from_millis(
+            DateTime::now().timestamp_millis() + LOCK_TIMEOUT.as_millis() as i64,
+        );
+        let result = self
+            .migrations
+            .update_one(
+                doc! {"_id": LOCK_ID, "owner": owner},
+                doc! {"$set": {"expires_at": expires_at}},
+                None,
+            )
+            .await?;
+        if result.matched_count == 0 {
+            bail!("lost the migration lock to another replica");
+        }
+        Ok(())
+    }
+
+    /// Renews the lock until it fails, only returns the error.
+    async fn keep_migration_lock(&self, owner: &str) -> anyhow::Error {
+        loop {
+            actix_web::rt::time::sleep(LOCK_RENEW_INTERVAL).await;
+            if let Err(e) = self.renew_migration_lock(owner).await {
+                return e.context("can not renew the migration lock");
+            }
+        }
+    }
+}
+
+fn user_defaults(db: &Database, dry_run: bool) -> BoxFuture<'_, Result<u64>> {
+    Box::pin(async move {
+        let users = db.collection::<Document>("users");
+        let filter = doc! {"$or": [
+            {"token_version": {"$exists": false}},
+            {"status": {"$exists": false}},
+            {"verification_sent_at": {"$exists": false}},
+            {"two_factor": {"$exists": false}},
+        ]};
+        if dry_run {
+            return Ok(users.count_documents(filter, None).await?);
+        }
+        let update = vec![doc! {"$set": {
+            "token_version": {"$ifNull": ["$token_version", 0]},
+            "status": {"$ifNull": ["$status", mongodb::bson::to_bson(&AccountStatus::Active)?]},
+            "verification_sent_at": {"$ifNull": ["$verification_sent_at", null]},
+            "two_factor": {"$ifNull": ["$two_factor", null]},
+        }}];
+        Ok(users
+            .update_many(filter, update, None)
+            .await?
+            .modified_count)
+    })
+}
+
+fn normalize_emails(db: &Database, dry_run: bool) -> BoxFuture<'_, Result<u64>> {
+    Box::pin(async move {
+        let users = db.collection::<Document>("users");
+        let filter = doc! {"email_normalized": {"$exists": false}};
+        if dry_run {
+            return Ok(users.count_documents(filter, None).await?);
+        }
+        let mut changed = 0;
+        let mut cursor = users.find(filter, None).await?;
+        while let Some(user) = cursor.next().await {
+            let user = user?;
+            users
+                .update_one(
+                    doc! {"_id": user.get_object_id("_id")?},
+                    doc! {"$set": {"email_normalized": normalize_email(user.get_str("email")?)}},
+                    None,
+                )
+                .await?;
+            changed += 1;
+        }
+        Ok(changed)
+    })
+}
+
+/// Fails if two users share an id or a normalized address, these have to be resolved by hand.
+fn user_indexes(db: &Database, dry_run: bool) -> BoxFuture<'_, Result<u64>> {
+    Box::pin(async move {
+        let users = db.collection::<Document>("users");
+        // listing fails if the collection does not exist yet
+        let existing = users.list_index_names().await.unwrap_or_default();
+        let mut changed = 0;
+        for field in ["id", "email_normalized"] {
+            if existing.contains(&format!("{}_1", field)) {
+                continue;
+            }
+            if !dry_run {
+                users
+                    .create_index(
+                        IndexModel::builder()
+                            .keys(doc! {field: 1})
+                            .options(IndexOptions::builder().unique(true).build())
+                            .build(),
+                        None,
+                    )
+                    .await?;
+            }
+            changed += 1;
+        }
+        Ok(changed)
+    })
+}
@@ -1,21 +1,215 @@
 use super::config::Config;
-use super::crypto;
-use crate::schema::{Role, User, UserWithHash, LoginRequest, UpdateRequest};
+use crate::metrics::MongoCommandMetrics;
+use crate::schema::{
+    AuthorizationCode, EmailChange, LoginAttempts, OidcClient, PasswordReset, RateLimitBucket,
+    RefreshToken, RevokedToken, Role, TwoFactorPolicy, UserWithHash, WebauthnChallenge,
+    WebauthnCredential,
+};
 use anyhow::{anyhow, Result};
 use futures_util::stream::StreamExt;
-use mongodb::options::Credential;
-use mongodb::{bson::doc, options::ClientOptions, Client, Collection};
-use tracing::{error, info, warn};
+use mongodb::bson::DateTime;
+use mongodb::options::{
+    Credential, FindOneAndUpdateOptions, IndexOptions, ReturnDocument, UpdateOptions,
+};
+use mongodb::bson::Document;
+use mongodb::{bson::doc, options::ClientOptions, Client, Collection, Database, IndexModel};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub mod migrations;
 
 #[derive(Clone)]
 pub struct Mongo {
-    users: Collection<UserWithHash>,
+    client: Client,
+    database: Database,
+    migrations: Collection<Document>,
+    refresh_tokens: Collection<RefreshToken>,
+    revoked_tokens: Collection<RevokedToken>,
+    clients: Collection<OidcClient>,
+    authorization_codes: Collection<AuthorizationCode>,
+    password_resets: Collection<PasswordReset>,
+    settings: Collection<TwoFactorPolicy>,
+    webauthn_credentials: Collection<WebauthnCredential>,
+    webauthn_challenges: Collection<WebauthnChallenge>,
+    login_attempts: Collection<LoginAttempts>,
+    rate_limits: Collection<RateLimitBucket>,
+    email_changes: Collection<EmailChange>,
+}
+
+/// Id of the document in `settings` holding the [`TwoFactorPolicy`].
+const TWO_FACTOR_POLICY_ID: &str = "two_factor_policy";
+
+async fn ping(client: &Client) -> Result<()> {
+    client
+        .database("admin")
+        .run_command(doc! {"ping": 1u32}, None)
+        .await?;
+    Ok(())
 }
 
 impl Mongo {
-    #[tracing::instrument(level="trace")]
-    pub async fn from_config(config: Config) -> Result<Self> {
+    /// Opens the connection, only waits for the server if the users or the rate limits are kept in mongo.
+    /// Then the indexes are created and pending migrations applied unless `SKIP_MIGRATIONS` is set,
+    /// the migrations only concern the users collection and are skipped with other user stores.
+    /// Otherwise the indexes are created in the background and the service starts without mongo.
+    #[tracing::instrument(level="trace", skip(config))]
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let mongo = Self::open(config).await?;
+        if !config.stores_in_mongo() {
+            info!("nothing is stored in mongo, not waiting for it");
+            let background = mongo.clone();
+            actix_web::rt::spawn(async move {
+                if let Err(e) = background.create_indexes().await {
+                    warn!("creating mongo indexes failed: {:?}", e);
+                }
+            });
+            return Ok(mongo);
+        }
+
+        mongo.connect().await?;
+        if !config.user_store.is_mongo() {
+            info!("users are not kept in mongo, skipping migrations");
+        } else if config.migrate_on_startup {
+            mongo.migrate(false).await?;
+        } else {
+            let pending = mongo.pending_migrations().await?;
+            if !pending.is_empty() {
+                warn!("migrations {:?} are pending, run `auth_service migrate`", pending);
+            }
+        }
+        Ok(mongo)
+    }
+
+    /// The database, for the user store kept in mongo.
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    /// Checks that the server still answers, for readiness probes.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn ping(&self) -> Result<()> {
+        ping(&self.client).await
+    }
+
+    /// Pings the server and creates the indexes.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn connect(&self) -> Result<()> {
+        // Ping the server to see if you can connect to the cluster
+        ping(&self.client).await?;
+        tracing::info!("Mongo Connection sucessfull");
+        self.create_indexes().await
+    }
+
+    /// Creates the indexes of all collections except users, these are managed by migrations.
+    #[tracing::instrument(level="trace", skip(self))]
+    async fn create_indexes(&self) -> Result<()> {
+        // revoked tokens only need to be kept until they expire anyway
+        self
+            .revoked_tokens
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .authorization_codes
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .password_resets
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .email_changes
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .webauthn_challenges
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .webauthn_credentials
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"credential_id": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .login_attempts
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .login_attempts
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"key": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .rate_limits
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self
+            .rate_limits
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"key": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
 
+    /// Sets up the client and the collections, nothing is sent to the server until the first operation.
+    pub async fn open(config: &Config) -> Result<Self> {
         let mut client_options = ClientOptions::parse(format!(
             "mongodb://{}:{}",
             config.db.address.clone(), config.db.port.clone()
@@ -29,165 +223,453 @@ impl Mongo {
             .password(config.db.password.clone())
             .build();
         client_options.credential = Some(cred);
+        client_options.command_event_handler = Some(Arc::new(MongoCommandMetrics));
 
         // Get a handle to the cluster
         let client = Client::with_options(client_options)?;
-        // Ping the server to see if you can connect to the cluster
-        client
-            .database("admin")
-            .run_command(doc! {"ping": 1u32}, None)
-            .await?;
-        tracing::info!("Mongo Connection sucessfull");
 
-        let mongo = Mongo {
-            users: client
+        Ok(Mongo {
+            client: client.clone(),
+            database: client.database("auth_server"),
+            migrations: client
+                .database("auth_server")
+                .collection::<Document>("_migrations"),
+            refresh_tokens: client
+                .database("auth_server")
+                .collection::<RefreshToken>("refresh_tokens"),
+            revoked_tokens: client
+                .database("auth_server")
+                .collection::<RevokedToken>("revoked_tokens"),
+            clients: client
+                .database("auth_server")
+                .collection::<OidcClient>("clients"),
+            authorization_codes: client
+                .database("auth_server")
+                .collection::<AuthorizationCode>("authorization_codes"),
+            password_resets: client
+                .database("auth_server")
+                .collection::<PasswordReset>("password_resets"),
+            settings: client
+                .database("auth_server")
+                .collection::<TwoFactorPolicy>("settings"),
+            webauthn_credentials: client
                 .database("auth_server")
-                .collection::<UserWithHash>("users"),
-        };
+                .collection::<WebauthnCredential>("webauthn_credentials"),
+            webauthn_challenges: client
+                .database("auth_server")
+                .collection::<WebauthnChallenge>("webauthn_challenges"),
+            login_attempts: client
+                .database("auth_server")
+                .collection::<LoginAttempts>("login_attempts"),
+            rate_limits: client
+                .database("auth_server")
+                .collection::<RateLimitBucket>("rate_limits"),
+            email_changes: client
+                .database("auth_server")
+                .collection::<EmailChange>("email_changes"),
+        })
+    }
 
-        match mongo
-            .users
-            .find_one(doc! {"email": &config.default_user.name.clone()}, None)
-            .await?
-        {
-            Some(_user) => {
-                if !mongo
-                    .verify_user(&LoginRequest {
-                        email: config.default_user.name.clone(),
-                        password: config.default_user.pass.clone(),
-                    })
-                    .await
-                {
-                    warn!("default user password changed from config");
-                }
-            }
-            None => {
-                if config.default_user.create {
-                    warn!("No default user, creating according to config");
-                    mongo
-                        .create_user(
-                            User {
-                                id: uuid::Uuid::new_v4().to_string(),
-                                name: "".into(),
-                                password: config.default_user.pass.clone(),
-                                email: config.default_user.name.clone(),
-                                roles: vec![Role::Admin, Role::Moderator, Role::User],
-                                image: None,
-                            }
-                            .into(),
-                        )
-                        .await?;
-                } else {
-                    error!("No default user");
-                    panic!("No default user");
-                }
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn insert_refresh_token(&self, token: &RefreshToken) -> Result<()> {
+        self.refresh_tokens.insert_one(token, None).await?;
+        Ok(())
+    }
+
+    /// Atomically marks an unused refresh token as used and returns it.
+    /// Returns `None` if the token is unknown, already used or revoked.
+    #[tracing::instrument(level="trace", skip(self, hash))]
+    pub async fn claim_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>> {
+        self.refresh_tokens
+            .find_one_and_update(
+                doc! {"hash": hash, "used": false, "revoked": false},
+                doc! {"$set": {"used": true}},
+                None,
+            )
+            .await
+            .map_err(Into::into)
+    }
+
+    #[tracing::instrument(level="trace", skip(self, hash))]
+    pub async fn get_refresh_token(&self, hash: &str) -> Result<RefreshToken> {
+        match self.refresh_tokens.find_one(doc! {"hash": hash}, None).await {
+            Ok(Some(t)) => Ok(t),
+            Ok(None) => Err(anyhow!("Refresh token not found")),
+            Err(e) => {
+                warn!("Error while searching for refresh token: {:?}", e);
+                Err(e.into())
             }
         }
-        Ok(mongo)
     }
 
     #[tracing::instrument(level="trace", skip(self))]
-    pub async fn create_user(&self, user: UserWithHash) -> Result<()> {
-        if self.get_user_from_email(&user.email).await.is_ok() {
-            return Err(anyhow!("User exists!"));
-        }
-        self.users.insert_one(&user, None).await?;
-        info!("adding user {:?} with roles {:?}", user.email, user.roles);
+    pub async fn revoke_refresh_family(&self, family: &str) -> Result<()> {
+        self.refresh_tokens
+            .update_many(doc! {"family": family}, doc! {"$set": {"revoked": true}}, None)
+            .await?;
         Ok(())
     }
 
     #[tracing::instrument(level="trace", skip(self))]
-    pub async fn verify_user(&self, request: &LoginRequest) -> bool {
-        match self.get_user_from_email(&request.email).await {
-            Err(_) => false,
-            Ok(user) => crypto::verify(user.hash.0, &request.password),
-        }
+    pub async fn revoke_user_refresh_tokens(&self, user_id: &str) -> Result<()> {
+        self.refresh_tokens
+            .update_many(doc! {"user_id": user_id}, doc! {"$set": {"revoked": true}}, None)
+            .await?;
+        Ok(())
     }
 
     #[tracing::instrument(level="trace", skip(self))]
-    pub async fn get_user_from_email(&self, email: &str) -> Result<UserWithHash> {
-        match self.users.find_one(doc! {"email": email}, None).await {
-            Ok(Some(u)) => Ok(u),
-            Ok(None) => Err(anyhow!("User not found")),
+    pub async fn revoke_token(&self, jti: &str, exp: i64) -> Result<()> {
+        self.revoked_tokens
+            .insert_one(
+                RevokedToken {
+                    jti: jti.to_string(),
+                    expires_at: DateTime::from_millis(exp * 1000),
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn is_token_revoked(&self, jti: &str) -> Result<bool> {
+        Ok(self.revoked_tokens.find_one(doc! {"jti": jti}, None).await?.is_some())
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn create_client(&self, client: &OidcClient) -> Result<()> {
+        self.clients.insert_one(client, None).await?;
+        info!("registered client {} ({})", client.name, client.client_id);
+        Ok(())
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn get_client(&self, client_id: &str) -> Result<OidcClient> {
+        match self.clients.find_one(doc! {"client_id": client_id}, None).await {
+            Ok(Some(c)) => Ok(c),
+            Ok(None) => Err(anyhow!("Client not found")),
             Err(e) => {
-                warn!("Error while searching for user: {:?}", e);
+                warn!("Error while searching for client: {:?}", e);
                 Err(e.into())
             }
         }
     }
 
     #[tracing::instrument(level="trace", skip(self))]
-    pub async fn get_user_from_id(&self, id: &str) -> Result<UserWithHash> {
-        match self.users.find_one(doc! {"id": id}, None).await {
-            Ok(Some(u)) => Ok(u),
-            Ok(None) => Err(anyhow!("User not found")),
+    pub async fn get_all_clients(&self) -> Result<Vec<OidcClient>> {
+        match self.clients.find(doc! {}, None).await {
+            Ok(cursor) => Ok(cursor.filter_map(|v| async { v.ok() }).collect().await),
             Err(e) => {
-                warn!("Error while searching for user: {:?}", e);
+                warn!("error while listing all clients: {:?}", e);
                 Err(e.into())
             }
         }
     }
 
     #[tracing::instrument(level="trace", skip(self))]
-    pub async fn update_user(&self, id: &str, update_request: &UpdateRequest) -> Result<()> {
-        let mut user = self.get_user_from_id(id).await?;
+    pub async fn delete_client(&self, client_id: &str) -> Result<()> {
+        self.clients.delete_one(doc! {"client_id": client_id}, None).await?;
+        Ok(())
+    }
 
-        match update_request {
-            UpdateRequest::User(update_request) => {
-                if let Some(password) = update_request.password.clone() {
-                    user.hash = crypto::hash(&password);
-                }
-                if let Some(email) = update_request.email.clone() {
-                    user.email = email;
-                }
-                if let Some(image) = update_request.image.clone() {
-                    user.image = Some(image);
-                }
-                if let Some(name) = update_request.name.clone() {
-                    user.name = name;
-                }
-                self.users.replace_one(doc! {"id": id}, &user, None).await?;
-            }
-            UpdateRequest::Admin(update_request) => {
-                if let Some(password) = update_request.password.clone() {
-                    user.hash = crypto::hash(&password);
-                }
-                if let Some(email) = update_request.email.clone() {
-                    user.email = email;
-                }
-                if let Some(image) = update_request.image.clone() {
-                    user.image = Some(image);
-                }
-                if let Some(name) = update_request.name.clone() {
-                    user.name = name;
-                }
-                if let Some(roles) = update_request.roles.clone() {
-                    user.roles = roles;
-                }
-                self.users.replace_one(doc! {"id": id}, &user, None).await?;
-            }
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn insert_authorization_code(&self, code: &AuthorizationCode) -> Result<()> {
+        self.authorization_codes.insert_one(code, None).await?;
+        Ok(())
+    }
+
+    /// Removes the code so it can only be redeemed once.
+    #[tracing::instrument(level="trace", skip(self, hash))]
+    pub async fn take_authorization_code(&self, hash: &str) -> Result<Option<AuthorizationCode>> {
+        self.authorization_codes
+            .find_one_and_delete(doc! {"hash": hash}, None)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Stores a reset token, replacing any earlier one of the user.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn insert_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        self.password_resets
+            .delete_many(doc! {"user_id": &reset.user_id}, None)
+            .await?;
+        self.password_resets.insert_one(reset, None).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level="trace", skip(self, hash))]
+    pub async fn get_password_reset(&self, hash: &str) -> Result<Option<PasswordReset>> {
+        self.password_resets
+            .find_one(doc! {"hash": hash}, None)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Removes the reset so the token can only be used once.
+    #[tracing::instrument(level="trace", skip(self, hash))]
+    pub async fn take_password_reset(&self, hash: &str) -> Result<Option<PasswordReset>> {
+        self.password_resets
+            .find_one_and_delete(doc! {"hash": hash}, None)
+            .await
+            .map_err(Into::into)
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn delete_password_resets(&self, user_id: &str) -> Result<()> {
+        self.password_resets
+            .delete_many(doc! {"user_id": user_id}, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Stores a pending email change, replacing any earlier one of the same kind.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn insert_email_change(&self, change: &EmailChange) -> Result<()> {
+        self.email_changes
+            .delete_many(doc! {"user_id": &change.user_id, "revert": change.revert}, None)
+            .await?;
+        self.email_changes.insert_one(change, None).await?;
+        Ok(())
+    }
+
+    /// Removes the change so the token can only be used once.
+    #[tracing::instrument(level="trace", skip(self, hash))]
+    pub async fn take_email_change(
+        &self,
+        hash: &str,
+        revert: bool,
+    ) -> Result<Option<EmailChange>> {
+        self.email_changes
+            .find_one_and_delete(doc! {"hash": hash, "revert": revert}, None)
+            .await
+            .map_err(Into::into)
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn delete_email_changes(&self, user_id: &str) -> Result<()> {
+        self.email_changes
+            .delete_many(doc! {"user_id": user_id}, None)
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn get_two_factor_policy(&self) -> Result<TwoFactorPolicy> {
+        Ok(self
+            .settings
+            .find_one(doc! {"id": TWO_FACTOR_POLICY_ID}, None)
+            .await?
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn set_two_factor_policy(&self, policy: &TwoFactorPolicy) -> Result<()> {
+        self.settings
+            .update_one(
+                doc! {"id": TWO_FACTOR_POLICY_ID},
+                doc! {"$set": {"required_for": mongodb::bson::to_bson(&policy.required_for)?}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The roles of a user, without those the policy only grants with two factor authentication.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn effective_roles(&self, user: &UserWithHash) -> Result<Vec<Role>> {
+        if user.has_two_factor() {
+            return Ok(user.roles.clone());
         }
+        let policy = self.get_two_factor_policy().await?;
+        Ok(user
+            .roles
+            .iter()
+            .filter(|r| !policy.required_for.contains(r))
+            .copied()
+            .collect())
+    }
 
-        
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn insert_webauthn_challenge(&self, challenge: &WebauthnChallenge) -> Result<()> {
+        self.webauthn_challenges.insert_one(challenge, None).await?;
+        Ok(())
+    }
+
+    /// Removes the challenge so it can only be answered once.
+    #[tracing::instrument(level="trace", skip(self, hash))]
+    pub async fn take_webauthn_challenge(
+        &self,
+        hash: &str,
+        ceremony: &str,
+    ) -> Result<Option<WebauthnChallenge>> {
+        self.webauthn_challenges
+            .find_one_and_delete(doc! {"hash": hash, "ceremony": ceremony}, None)
+            .await
+            .map_err(Into::into)
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn insert_webauthn_credential(&self, credential: &WebauthnCredential) -> Result<()> {
+        self.webauthn_credentials.insert_one(credential, None).await?;
+        info!("adding webauthn credential for user {}", credential.user_id);
         Ok(())
     }
 
     #[tracing::instrument(level="trace", skip(self))]
-    pub async fn delete_user(&self, id: &str) -> Result<()> {
-        match self.users.delete_one(doc! {"id": id}, None).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow::Error::new(e)),
+    pub async fn get_webauthn_credential(&self, credential_id: &str) -> Result<WebauthnCredential> {
+        self.webauthn_credentials
+            .find_one(doc! {"credential_id": credential_id}, None)
+            .await?
+            .ok_or_else(|| anyhow!("Credential not found"))
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn get_webauthn_credentials(&self, user_id: &str) -> Result<Vec<WebauthnCredential>> {
+        Ok(self
+            .webauthn_credentials
+            .find(doc! {"user_id": user_id}, None)
+            .await?
+            .filter_map(|v| async { v.ok() })
+            .collect()
+            .await)
+    }
+
+    /// Stores the new signature counter, returns false if the credential was used concurrently.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn update_webauthn_sign_count(
+        &self,
+        credential_id: &str,
+        old: u32,
+        new: u32,
+    ) -> Result<bool> {
+        let result = self
+            .webauthn_credentials
+            .update_one(
+                doc! {"credential_id": credential_id, "sign_count": old},
+                doc! {"$set": {"sign_count": new}},
+                None,
+            )
+            .await?;
+        Ok(result.matched_count == 1)
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn delete_webauthn_credential(&self, user_id: &str, credential_id: &str) -> Result<()> {
+        let result = self
+            .webauthn_credentials
+            .delete_one(doc! {"user_id": user_id, "credential_id": credential_id}, None)
+            .await?;
+        if result.deleted_count == 0 {
+            return Err(anyhow!("Credential not found"));
         }
+        Ok(())
     }
 
     #[tracing::instrument(level="trace", skip(self))]
-    pub async fn get_all_users(&self) -> Result<Vec<UserWithHash>> {
-        return match self.users.find(doc! {}, None).await {
-            Ok(cursor) => Ok(cursor.filter_map(|v| async { v.ok() }).collect().await),
-            Err(e) => {
-                warn!("error while listing all users: {:?}", e);
-                Err(e.into())
-            }
-        };
+    pub async fn get_login_attempts(&self, keys: &[String]) -> Result<Vec<LoginAttempts>> {
+        Ok(self
+            .login_attempts
+            .find(doc! {"key": {"$in": keys}}, None)
+            .await?
+            .filter_map(|v| async { v.ok() })
+            .collect()
+            .await)
+    }
+
+    /// Counts a failed sign in and returns the updated attempts.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn record_login_failure(
+        &self,
+        key: &str,
+        now: DateTime,
+        expires_at: DateTime,
+    ) -> Result<LoginAttempts> {
+        self.login_attempts
+            .find_one_and_update(
+                doc! {"key": key},
+                doc! {
+                    "$inc": {"failures": 1},
+                    "$set": {"last_failure": now, "expires_at": expires_at},
+                },
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| anyhow!("upsert returned no document"))
+    }
+
+    /// Locks the key until `until`, the failures start over afterwards.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn lock_login(&self, key: &str, until: DateTime) -> Result<()> {
+        self.login_attempts
+            .update_one(
+                doc! {"key": key},
+                doc! {"$set": {"failures": 0, "locked_until": until, "expires_at": until}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn clear_login_attempts(&self, keys: &[String]) -> Result<u64> {
+        Ok(self
+            .login_attempts
+            .delete_many(doc! {"key": {"$in": keys}}, None)
+            .await?
+            .deleted_count)
+    }
+
+    /// Refills the bucket and takes a token in a single update, so replicas can share it.
+    #[tracing::instrument(level="trace", skip(self))]
+    pub async fn take_rate_limit_token(
+        &self,
+        key: &str,
+        capacity: f64,
+        rate: f64,
+        now: f64,
+    ) -> Result<RateLimitBucket> {
+        let full_at = DateTime::from_millis(((now + capacity / rate) * 1000.0) as i64);
+        let pipeline = vec![
+            doc! {"$set": {
+                "tokens": {"$min": [
+                    capacity,
+                    {"$add": [
+                        {"$ifNull": ["$tokens", capacity]},
+                        {"$multiply": [{"$subtract": [now, {"$ifNull": ["$updated", now]}]}, rate]},
+                    ]},
+                ]},
+                "updated": now,
+            }},
+            doc! {"$set": {"allowed": {"$gte": ["$tokens", 1.0]}}},
+            doc! {"$set": {
+                "tokens": {"$cond": ["$allowed", {"$subtract": ["$tokens", 1.0]}, "$tokens"]},
+                "expires_at": full_at,
+            }},
+        ];
+        self.rate_limits
+            .find_one_and_update(
+                doc! {"key": key},
+                pipeline,
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| anyhow!("upsert returned no document"))
+    }
+}
+
+/// Whether a write was rejected by a unique index.
+pub fn is_duplicate_key(err: &anyhow::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
+    match err.downcast_ref::<mongodb::error::Error>().map(|e| e.kind.as_ref()) {
+        Some(ErrorKind::Write(WriteFailure::WriteError(e))) => e.code == 11000,
+        Some(ErrorKind::Command(e)) => e.code == 11000,
+        _ => false,
     }
 }
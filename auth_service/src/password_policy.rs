@@ -0,0 +1,333 @@
+use actix_web::web;
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::config::PasswordPolicyConfig;
+use crate::schema::FieldError;
+
+/// Where breached passwords are looked up, both hold upper case SHA-1 hashes.
+enum BreachedList {
+    None,
+    /// A file with one `HASH` or `HASH:COUNT` per line, loaded into memory.
+    Hashes(HashSet<String>),
+    /// A k-anonymity dump, a directory with a file per 5 character hash prefix
+    /// holding the `SUFFIX:COUNT` lines of that prefix.
+    PrefixDir(PathBuf),
+}
+
+pub struct PasswordPolicy {
+    config: PasswordPolicyConfig,
+    breached: BreachedList,
+}
+
+impl PasswordPolicy {
+    pub fn new(config: PasswordPolicyConfig) -> Result<Self> {
+        let breached = match &config.breached_list {
+            None => BreachedList::None,
+            Some(path) if fs::metadata(path)?.is_dir() => BreachedList::PrefixDir(path.into()),
+            Some(path) => {
+                let hashes: HashSet<String> = fs::read_to_string(path)
+                    .with_context(|| format!("reading breached passwords from {}", path))?
+                    .lines()
+                    .filter_map(|line| line.split(':').next())
+                    .map(|hash| hash.trim().to_uppercase())
+                    .filter(|hash| !hash.is_empty())
+                    .collect();
+                info!("loaded {} breached password hashes", hashes.len());
+                BreachedList::Hashes(hashes)
+            }
+        };
+        Ok(Self { config, breached })
+    }
+
+    /// Checks a new password of the user with `email` and `name`,
+    /// returns an error for every rule that is violated.
+    pub async fn check(&self, password: &str, email: &str, name: &str) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        if password.chars().count() < self.config.min_length {
+            errors.push(FieldError::password(
+                "too_short",
+                format!("must be at least {} characters", self.config.min_length),
+            ));
+        }
+
+        let lower = password.to_lowercase();
+        let local_part = email.split('@').next().unwrap_or_default().to_lowercase();
+        if [email.to_lowercase(), local_part, name.to_lowercase()]
+            .iter()
+            .any(|personal| personal.chars().count() >= 3 && lower.contains(personal.as_str()))
+        {
+            errors.push(FieldError::password(
+                "personal_info",
+                "must not contain the email or name".into(),
+            ));
+        }
+
+        if strength_score(password) < self.config.min_score {
+            errors.push(FieldError::password(
+                "too_weak",
+                "is too easy to guess, use a longer password or more unrelated words".into(),
+            ));
+        }
+
+        if self.is_breached(password).await {
+            errors.push(FieldError::password(
+                "breached",
+                "appeared in a data breach, choose a different password".into(),
+            ));
+        }
+
+        errors
+    }
+
+    async fn is_breached(&self, password: &str) -> bool {
+        let hash: String = Sha1::digest(password.as_bytes())
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        match &self.breached {
+            BreachedList::None => false,
+            BreachedList::Hashes(hashes) => hashes.contains(&hash),
+            BreachedList::PrefixDir(dir) => {
+                // the files are read on the blocking pool, not on the worker serving requests
+                let dir = dir.clone();
+                let prefix = hash[..5].to_string();
+                match web::block(move || in_prefix_dir(&dir, &hash)).await {
+                    Ok(Ok(found)) => found,
+                    Ok(Err(e)) => {
+                        warn!("no breached passwords for prefix {}: {:?}", prefix, e);
+                        false
+                    }
+                    Err(e) => {
+                        warn!("breached password lookup for prefix {} failed: {:?}", prefix, e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Looks for `hash` in the file of its prefix, named with or without `.txt`.
+fn in_prefix_dir(dir: &Path, hash: &str) -> io::Result<bool> {
+    let (prefix, suffix) = hash.split_at(5);
+    let content = fs::read_to_string(dir.join(prefix))
+        .or_else(|_| fs::read_to_string(dir.join(format!("{}.txt", prefix))))?;
+    Ok(content
+        .lines()
+        .any(|line| line.split(':').next().map(str::trim) == Some(suffix)))
+}
+
+/// Words that make up a large share of leaked passwords, a match counts like one
+/// pick from a dictionary instead of its single characters.
+const COMMON_WORDS: &[&str] = &[
+    "password", "passw0rd", "qwerty", "qwertz", "azerty", "asdf", "zxcv", "letmein", "welcome",
+    "admin", "login", "changeme", "default", "secret", "dragon", "monkey", "master", "shadow",
+    "football", "baseball", "soccer", "iloveyou", "sunshine", "princess", "superman", "batman",
+    "starwars", "trustno1", "whatever", "freedom", "hello", "summer", "winter", "spring", "autumn",
+];
+
+/// log10 of the guesses for one dictionary word, roughly a 10000 word dictionary.
+const DICTIONARY_GUESSES_LOG10: f64 = 4.0;
+
+/// Estimates how hard a password is to guess on the 0 to 4 scale of zxcvbn.
+/// Common words, repeated characters and runs like `abc` or `321` add almost nothing to the estimate.
+pub fn strength_score(password: &str) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+
+    let mut charset = 0f64;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        charset += 26.0;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        charset += 26.0;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        charset += 10.0;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        charset += 33.0;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        charset += 100.0;
+    }
+
+    let lower: Vec<char> = password.to_lowercase().chars().collect();
+    let mut covered = vec![false; lower.len()];
+    let mut guesses_log10 = 0f64;
+    for word in COMMON_WORDS {
+        let word: Vec<char> = word.chars().collect();
+        let mut i = 0;
+        while i + word.len() <= lower.len() {
+            if lower[i..i + word.len()] == word[..] && !covered[i] {
+                covered[i..i + word.len()].iter_mut().for_each(|c| *c = true);
+                guesses_log10 += DICTIONARY_GUESSES_LOG10;
+                i += word.len();
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    // characters continuing a repeat or a sequence count as a tenth
+    for (i, c) in chars.iter().enumerate() {
+        if covered.get(i).copied().unwrap_or(false) {
+            continue;
+        }
+        let predictable = i > 0 && {
+            let step = *c as i64 - chars[i - 1] as i64;
+            step == 0 || step.abs() == 1
+        };
+        guesses_log10 += if predictable { 0.1 } else { 1.0 } * charset.max(1.0).log10();
+    }
+
+    match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SHA-1 of "password"
+    const PASSWORD_HASH: &str = "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8";
+
+    fn policy(breached_list: Option<&Path>) -> PasswordPolicy {
+        PasswordPolicy::new(PasswordPolicyConfig {
+            min_length: 10,
+            min_score: 3,
+            breached_list: breached_list.map(|path| path.to_str().unwrap().to_string()),
+        })
+        .unwrap()
+    }
+
+    async fn codes(policy: &PasswordPolicy, password: &str) -> Vec<String> {
+        policy
+            .check(password, "jane.doe@board.test", "Jane")
+            .await
+            .into_iter()
+            .map(|error| error.code)
+            .collect()
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("password_policy_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn scores_weak_medium_and_strong_passwords() {
+        let cases: &[(&str, u8, u8)] = &[
+            ("", 0, 0),
+            ("aaaaaaaaaaaa", 0, 0),
+            ("abcdefghijkl", 0, 0),
+            ("123456789", 0, 0),
+            ("password", 0, 1),
+            ("qwerty123", 0, 1),
+            ("letmein!", 0, 1),
+            ("Password1!", 2, 3),
+            ("sunshine2023", 2, 3),
+            ("password123password", 2, 3),
+            ("correct horse battery staple", 4, 4),
+            ("9vL!k2#Qz8@w", 4, 4),
+            ("Ünïcødé-pässwörd", 4, 4),
+        ];
+        for (password, min, max) in cases {
+            let score = strength_score(password);
+            assert!(
+                (*min..=*max).contains(&score),
+                "{:?} scored {}, expected {} to {}",
+                password,
+                score,
+                min,
+                max
+            );
+        }
+    }
+
+    #[test]
+    fn common_words_count_as_one_guess() {
+        assert!(strength_score("passwordpassword") < strength_score("pxsswqrdpbsswo"));
+        assert!(strength_score("PASSWORD") <= 1);
+    }
+
+    #[actix_web::test]
+    async fn reports_every_violated_rule() {
+        let policy = policy(None);
+        assert_eq!(
+            codes(&policy, "correct horse battery staple").await,
+            Vec::<String>::new()
+        );
+        assert_eq!(codes(&policy, "x7!Qz").await, ["too_short"]);
+        assert_eq!(
+            codes(&policy, "correct jane.doe staple").await,
+            ["personal_info"]
+        );
+        assert_eq!(
+            codes(&policy, "Correct JANE battery").await,
+            ["personal_info"]
+        );
+        assert_eq!(codes(&policy, "aaaaaaaaaaaa").await, ["too_weak"]);
+        assert_eq!(codes(&policy, "aaaa").await, ["too_short", "too_weak"]);
+    }
+
+    #[actix_web::test]
+    async fn matches_a_file_of_breached_hashes() {
+        let dir = scratch_dir("file");
+        let file = dir.join("hashes.txt");
+        fs::write(
+            &file,
+            format!(
+                "0000000000000000000000000000000000000000:3\n{}:9545824\n",
+                PASSWORD_HASH.to_lowercase()
+            ),
+        )
+        .unwrap();
+        let policy = policy(Some(&file));
+
+        assert!(policy.is_breached("password").await);
+        assert!(!policy.is_breached("Password").await);
+        assert!(!policy.is_breached("correct horse battery staple").await);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn matches_a_prefix_directory() {
+        let dir = scratch_dir("prefix");
+        let (prefix, suffix) = PASSWORD_HASH.split_at(5);
+        fs::write(
+            dir.join(format!("{}.txt", prefix)),
+            format!(
+                "0018A45C4D1DEF81644B54AB7F969B88D65:1\n{}:9545824\n",
+                suffix
+            ),
+        )
+        .unwrap();
+        let policy = policy(Some(&dir));
+
+        assert!(policy.is_breached("password").await);
+        assert!(codes(&policy, "password")
+            .await
+            .contains(&"breached".to_string()));
+        // same prefix file, different suffix
+        assert!(!in_prefix_dir(&dir, &format!("{}{}", prefix, "0".repeat(35))).unwrap());
+        // no file for the prefix is not a match
+        assert!(!policy.is_breached("correct horse battery staple").await);
+        assert!(in_prefix_dir(&dir, "FFFFF00000000000000000000000000000000000").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,13 @@
 use crate::{crypto, image_service::ImageService};
 use actix_web::web::Json;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use derivative::Derivative;
+use mongodb::bson::DateTime;
 use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::pwhash::argon2id13::HashedPassword;
+use std::collections::BTreeMap;
 use std::ops::Add;
+use std::str::FromStr;
 use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
@@ -15,12 +18,48 @@ pub enum Role {
     Admin,
 }
 
-#[derive(Serialize, Deserialize)]
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "User" => Ok(Role::User),
+            "Moderator" => Ok(Role::Moderator),
+            "Admin" => Ok(Role::Admin),
+            _ => Err(anyhow!("unknown role {}", s)),
+        }
+    }
+}
+
+/// Accounts created by sign up stay pending until the email address is verified.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Pending,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Status {
     Ok,
     Failed,
 }
 
+/// Answer of the health routes, `checks` is empty for liveness.
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, DependencyHealth>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DependencyHealth {
+    pub status: Status,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
 // #[derive(Serialize, Deserialize)]
 // pub struct Resp {
 //     pub(crate) status: Status,
@@ -31,9 +70,405 @@ pub enum Status {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TokenResponse {
     pub(crate) token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) refresh_token: Option<String>,
     pub(crate) user: UserInfoFull,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyInfo {
+    pub kid: String,
+    pub active: bool,
+    pub can_sign: bool,
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct RefreshRequest {
+    #[derivative(Debug = "ignore")]
+    pub refresh_token: String,
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct LogoutRequest {
+    #[derivative(Debug = "ignore")]
+    pub refresh_token: Option<String>,
+}
+
+/// A JWT that was logged out before it expired.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub expires_at: DateTime,
+}
+
+/// TOTP enrollment of a user, unconfirmed until the first code was entered.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct TwoFactor {
+    #[derivative(Debug = "ignore")]
+    pub secret: String,
+    pub confirmed: bool,
+    /// Last used time step, codes can not be used twice.
+    #[serde(default)]
+    pub last_step: i64,
+    /// Hashes of the unused recovery codes.
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+/// A TOTP or recovery code.
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct TwoFactorCode {
+    #[derivative(Debug = "ignore")]
+    pub code: String,
+}
+
+/// Disables 2FA, needs a code and step up authentication.
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct TwoFactorDisableRequest {
+    #[derivative(Debug = "ignore")]
+    pub code: String,
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub current_password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Roles that are only granted to users with two factor authentication.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TwoFactorPolicy {
+    #[serde(default)]
+    pub required_for: Vec<Role>,
+}
+
+pub const MFA_LOGIN: &str = "mfa_login";
+
+/// Claims of the token that links the two steps of a sign in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MfaClaims {
+    pub exp: i64,
+    pub user_id: String,
+    pub ver: u32,
+    pub purpose: String,
+}
+
+impl MfaClaims {
+    pub fn new(user: &UserWithHash, lifetime: Duration) -> Self {
+        Self {
+            exp: OffsetDateTime::now_utc().add(lifetime).unix_timestamp(),
+            user_id: user.id.clone(),
+            ver: user.token_version,
+            purpose: MFA_LOGIN.to_string(),
+        }
+    }
+}
+
+/// Returned by sign in instead of tokens if the user has to enter a second factor.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum SignInResponse {
+    Token(TokenResponse),
+    Mfa(MfaChallenge),
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct MfaLoginRequest {
+    #[derivative(Debug = "ignore")]
+    pub mfa_token: String,
+    #[derivative(Debug = "ignore")]
+    pub code: String,
+}
+
+/// A passkey or security key of a user.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct WebauthnCredential {
+    pub credential_id: String,
+    pub user_id: String,
+    /// Uncompressed P-256 point, base64url encoded.
+    #[derivative(Debug = "ignore")]
+    pub public_key: String,
+    pub sign_count: u32,
+    pub name: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebauthnCredentialInfo {
+    pub credential_id: String,
+    pub name: String,
+    pub created_at: i64,
+}
+
+impl From<WebauthnCredential> for WebauthnCredentialInfo {
+    fn from(credential: WebauthnCredential) -> Self {
+        Self {
+            credential_id: credential.credential_id,
+            name: credential.name,
+            created_at: credential.created_at.timestamp_millis() / 1000,
+        }
+    }
+}
+
+/// A pending ceremony, only the hash of the challenge is stored.
+/// Registrations are bound to the user, logins only if an email was given.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct WebauthnChallenge {
+    #[derivative(Debug = "ignore")]
+    pub hash: String,
+    pub ceremony: String,
+    pub user_id: Option<String>,
+    pub expires_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelyingPartyEntity {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnUserEntity {
+    /// base64url encoded user id.
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub alg: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+}
+
+impl CredentialDescriptor {
+    pub fn public_key(id: String) -> Self {
+        Self {
+            kind: "public-key".into(),
+            id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+/// Options for `navigator.credentials.create()`, binary values are base64url encoded.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialCreationOptions {
+    pub rp: RelyingPartyEntity,
+    pub user: WebauthnUserEntity,
+    pub challenge: String,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    pub timeout: u32,
+    pub attestation: String,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+}
+
+/// Options for `navigator.credentials.get()`, binary values are base64url encoded.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialRequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub timeout: u32,
+    pub user_verification: String,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebauthnLoginStart {
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// Result of `navigator.credentials.create()`.
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct RegisterCredentialRequest {
+    pub id: String,
+    pub response: AttestationResponse,
+    /// Name shown in the list of credentials.
+    pub name: Option<String>,
+    /// For step up authentication, like [`StepUpRequest`].
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub current_password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+/// Result of `navigator.credentials.get()`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginCredentialRequest {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+/// Failed sign ins of an account or a client ip, `key` is `account:<email>` or `ip:<address>`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginAttempts {
+    pub key: String,
+    pub failures: u32,
+    pub last_failure: DateTime,
+    #[serde(default)]
+    pub locked_until: Option<DateTime>,
+    /// Attempts are forgotten after a quiet period.
+    pub expires_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnlockRequest {
+    pub email: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Token bucket of the mongo rate limit backend, times are unix timestamps in seconds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitBucket {
+    pub key: String,
+    pub tokens: f64,
+    pub updated: f64,
+    /// Whether the last request took a token.
+    pub allowed: bool,
+    /// Full buckets are removed.
+    pub expires_at: DateTime,
+}
+
+/// A rejected field of a request, `code` is meant for the frontend and `message` for the user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn password(code: &str, message: String) -> Self {
+        Self {
+            field: "password".into(),
+            code: code.into(),
+            message,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+pub const VERIFY_EMAIL: &str = "verify_email";
+
+/// Claims of links sent by mail.
+/// `purpose` keeps a link from being used for anything else,
+/// `email` invalidates the link once the address of the user changed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmailClaims {
+    pub exp: i64,
+    pub user_id: String,
+    pub email: String,
+    pub purpose: String,
+}
+
+impl EmailClaims {
+    pub fn new(user: &UserWithHash, purpose: &str, lifetime: Duration) -> Self {
+        Self {
+            exp: OffsetDateTime::now_utc().add(lifetime).unix_timestamp(),
+            user_id: user.id.clone(),
+            email: user.email.clone(),
+            purpose: purpose.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenQuery {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailRequest {
+    pub email: String,
+}
+
+/// Query of the forward auth endpoint.
+/// `role` is a comma separated list of roles of which the user needs at least one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForwardAuthQuery {
+    pub role: Option<String>,
+}
+
+/// Server side record of an opaque refresh token.
+/// Only the hash of the token is stored, every rotation stays in the same family.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct RefreshToken {
+    #[derivative(Debug = "ignore")]
+    pub hash: String,
+    pub family: String,
+    pub user_id: String,
+    pub exp: i64,
+    pub used: bool,
+    pub revoked: bool,
+    /// Carried over to the tokens issued on refresh.
+    #[serde(default)]
+    pub auth_time: i64,
+}
+
 #[derive(Serialize, Deserialize, Derivative)]
 #[derivative(Debug)]
 pub struct RegisteringUser {
@@ -66,10 +501,38 @@ impl RegisteringUser {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserClaims {
-    exp: i64,
+    pub exp: i64,
     nbf: i64,
-    sub: String,
+    pub sub: String,
     pub user_id: String,
+    #[serde(default)]
+    pub jti: String,
+    /// `token_version` of the user at the time the token was issued
+    #[serde(default)]
+    pub ver: u32,
+    /// Scopes granted to an OIDC client, first party tokens carry none and may access everything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Unix timestamp of the sign in the token descends from.
+    #[serde(default)]
+    pub auth_time: i64,
+    /// Client an OIDC access token was issued to, first party tokens carry none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+}
+
+impl UserClaims {
+    /// Access tokens of OIDC clients only grant their scopes, not the first party api.
+    /// Tokens issued before `aud` was set are recognized by their scope.
+    pub fn is_oidc(&self) -> bool {
+        self.aud.is_some() || self.scope.is_some()
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope
+            .as_ref()
+            .is_none_or(|s| s.split(' ').any(|s| s == scope))
+    }
 }
 
 impl From<UserWithHash> for UserClaims {
@@ -81,6 +544,11 @@ impl From<UserWithHash> for UserClaims {
             nbf: OffsetDateTime::now_utc().unix_timestamp(),
             sub: uh.name.clone(),
             user_id: uh.id,
+            jti: Uuid::new_v4().to_string(),
+            ver: uh.token_version,
+            scope: None,
+            auth_time: OffsetDateTime::now_utc().unix_timestamp(),
+            aud: None,
         }
     }
 }
@@ -93,9 +561,42 @@ pub struct UserWithHash {
     #[derivative(Debug = "ignore")]
     pub hash: HashedPassword,
     pub email: String,
+    /// `email` as returned by [`normalize_email`], used for lookups and uniqueness.
+    #[serde(default)]
+    pub email_normalized: String,
     pub roles: Vec<Role>,
     #[derivative(Debug = "ignore")]
     pub image: Option<String>,
+    /// Incremented to invalidate every token issued before.
+    #[serde(default)]
+    pub token_version: u32,
+    #[serde(default)]
+    pub status: AccountStatus,
+    /// Unix timestamp of the last verification mail.
+    #[serde(default)]
+    pub verification_sent_at: Option<i64>,
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub two_factor: Option<TwoFactor>,
+}
+
+impl UserWithHash {
+    pub fn has_two_factor(&self) -> bool {
+        self.two_factor.as_ref().is_some_and(|tf| tf.confirmed)
+    }
+}
+
+/// Normalizes an address for comparisons, `Bob@Bücher.example` and `bob@xn--bcher-kva.example` are the same.
+/// The local part is lowercased, the domain converted to its lowercase ascii (IDNA) form.
+pub fn normalize_email(email: &str) -> String {
+    let email = email.trim();
+    match email.rsplit_once('@') {
+        Some((local, domain)) => {
+            let domain = idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase());
+            format!("{}@{}", local.to_lowercase(), domain)
+        }
+        None => email.to_lowercase(),
+    }
 }
 
 impl From<User> for UserWithHash {
@@ -104,9 +605,14 @@ impl From<User> for UserWithHash {
             id: user.id,
             name: user.name,
             hash: crypto::hash(&user.password),
+            email_normalized: normalize_email(&user.email),
             email: user.email,
             roles: user.roles,
             image: user.image,
+            token_version: 0,
+            status: AccountStatus::Active,
+            verification_sent_at: None,
+            two_factor: None,
         }
     }
 }
@@ -141,6 +647,7 @@ pub struct UserInfoFull {
     pub roles: Vec<Role>,
     #[derivative(Debug = "ignore")]
     pub image: Option<String>,
+    pub status: AccountStatus,
 }
 
 impl From<UserWithHash> for UserInfoFull {
@@ -151,6 +658,7 @@ impl From<UserWithHash> for UserInfoFull {
             email: uh.email,
             roles: uh.roles,
             image: uh.image,
+            status: uh.status,
         }
     }
 }
@@ -185,6 +693,18 @@ pub struct UpdateRequestUser {
     pub password: Option<String>,
     #[derivative(Debug = "ignore")]
     pub image: Option<String>,
+    /// Needed to change the password or email without a recent sign in.
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub current_password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Derivative, Default)]
+#[derivative(Debug)]
+pub struct StepUpRequest {
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub current_password: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Derivative)]
@@ -217,3 +737,354 @@ impl From<Json<UpdateRequestAdmin>> for UpdateRequest {
         Self::Admin(ur.0)
     }
 }
+
+/// Claims of a machine token issued to a service client.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientClaims {
+    pub exp: i64,
+    nbf: i64,
+    sub: String,
+    pub client_id: String,
+    pub jti: String,
+    pub scope: String,
+}
+
+impl ClientClaims {
+    pub fn new(client_id: &str, scope: String, lifetime: Duration) -> Self {
+        let now = OffsetDateTime::now_utc();
+        Self {
+            exp: now.add(lifetime).unix_timestamp(),
+            nbf: now.unix_timestamp(),
+            sub: client_id.to_string(),
+            client_id: client_id.to_string(),
+            jti: Uuid::new_v4().to_string(),
+            scope,
+        }
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split(' ').any(|s| s == scope)
+    }
+}
+
+pub const GRANT_AUTHORIZATION_CODE: &str = "authorization_code";
+pub const GRANT_CLIENT_CREDENTIALS: &str = "client_credentials";
+
+fn default_grant_types() -> Vec<String> {
+    vec![GRANT_AUTHORIZATION_CODE.to_string()]
+}
+
+/// An application that uses the board accounts through OpenID Connect,
+/// or a service that calls the api with its own identity.
+/// Public clients have no secret and must use PKCE.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct OidcClient {
+    pub client_id: String,
+    pub name: String,
+    #[derivative(Debug = "ignore")]
+    pub secret: Option<HashedPassword>,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default = "default_grant_types")]
+    pub grant_types: Vec<String>,
+    /// Scopes a service client may request with the client credentials grant.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisteringClient {
+    pub name: String,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    pub confidential: bool,
+    #[serde(default = "default_grant_types")]
+    pub grant_types: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct ClientCredentials {
+    pub client_id: String,
+    #[derivative(Debug = "ignore")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub name: String,
+    pub confidential: bool,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+impl From<OidcClient> for ClientInfo {
+    fn from(client: OidcClient) -> Self {
+        Self {
+            client_id: client.client_id,
+            name: client.name,
+            confidential: client.secret.is_some(),
+            redirect_uris: client.redirect_uris,
+            grant_types: client.grant_types,
+            scopes: client.scopes,
+        }
+    }
+}
+
+/// A pending authorization code, only the hash of the code is stored.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct AuthorizationCode {
+    #[derivative(Debug = "ignore")]
+    pub hash: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    /// Sign in of the user that confirmed the request, passed on to the access token.
+    #[serde(default)]
+    pub auth_time: i64,
+    pub expires_at: DateTime,
+}
+
+/// A pending password reset, only the hash of the token is stored.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct PasswordReset {
+    #[derivative(Debug = "ignore")]
+    pub hash: String,
+    pub user_id: String,
+    pub expires_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct ResetPasswordRequest {
+    #[derivative(Debug = "ignore")]
+    pub token: String,
+    #[derivative(Debug = "ignore")]
+    pub password: String,
+}
+
+/// An applied migration in `_migrations`, see [`crate::mongo::migrations`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MigrationRecord {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub description: String,
+    pub applied_at: DateTime,
+    pub changed: u64,
+}
+
+/// A pending change of the email address, only the hash of the token is stored.
+/// Confirmations are mailed to `new_email`, reverts to `old_email` after the change.
+#[derive(Serialize, Deserialize, Clone, Derivative)]
+#[derivative(Debug)]
+pub struct EmailChange {
+    #[derivative(Debug = "ignore")]
+    pub hash: String,
+    pub user_id: String,
+    pub old_email: String,
+    pub new_email: String,
+    pub revert: bool,
+    pub expires_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthorizeRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthorizeResponse {
+    pub redirect_to: String,
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    #[derivative(Debug = "ignore")]
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub client_id: Option<String>,
+    #[derivative(Debug = "ignore")]
+    pub client_secret: Option<String>,
+    #[derivative(Debug = "ignore")]
+    pub code_verifier: Option<String>,
+    pub scope: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct OidcTokenResponse {
+    #[derivative(Debug = "ignore")]
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub scope: String,
+    #[derivative(Debug = "ignore")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub user: UserInfoClaims,
+}
+
+/// Standard claims about a user, filtered by the granted scopes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserInfoClaims {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<Role>>,
+}
+
+impl UserInfoClaims {
+    /// `roles` are the effective roles of the user, see [`crate::mongo::Mongo::effective_roles`].
+    pub fn new(user: UserInfoFull, roles: Vec<Role>, claims: &UserClaims) -> Self {
+        let profile = claims.has_scope("profile");
+        let email = claims.has_scope("email");
+        Self {
+            sub: user.id,
+            name: Some(user.name).filter(|_| profile),
+            email: Some(user.email).filter(|_| email),
+            email_verified: Some(user.status == AccountStatus::Active).filter(|_| email),
+            roles: Some(roles).filter(|_| profile),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Derivative)]
+#[derivative(Debug)]
+pub struct IntrospectionRequest {
+    #[derivative(Debug = "ignore")]
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    #[derivative(Debug = "ignore")]
+    pub client_secret: Option<String>,
+}
+
+/// Token introspection response as defined in RFC 7662.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<Role>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub response_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub introspection_endpoint: String,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_emails() {
+        let cases = [
+            ("bob@example.com", "bob@example.com"),
+            // case and surrounding whitespace
+            ("Bob@Example.COM", "bob@example.com"),
+            ("  BOB@example.com\n", "bob@example.com"),
+            ("Jürgen@Example.com", "jürgen@example.com"),
+            // unicode domains are compared in their IDNA form
+            ("Bob@Bücher.example", "bob@xn--bcher-kva.example"),
+            ("bob@BÜCHER.Example", "bob@xn--bcher-kva.example"),
+            ("bob@xn--bcher-kva.example", "bob@xn--bcher-kva.example"),
+            ("bob@XN--BCHER-KVA.example", "bob@xn--bcher-kva.example"),
+            ("bob@\u{200B}example.com", "bob@example.com"),
+            // the domain starts after the last @
+            ("\"a@b\"@Example.com", "\"a@b\"@example.com"),
+            // domains IDNA rejects are only lowercased
+            ("bob@XN--A.Example", "bob@xn--a.example"),
+            ("bob@Exa\u{FFFD}mple.COM", "bob@exa\u{FFFD}mple.com"),
+            // no domain at all
+            ("Bob", "bob"),
+            ("Bob@", "bob@"),
+        ];
+        for (email, normalized) in cases {
+            assert_eq!(
+                normalize_email(email),
+                normalized,
+                "normalizing {:?}",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn addresses_of_one_user_share_the_normalized_form() {
+        let user: UserWithHash = User {
+            id: "id".into(),
+            name: "bob".into(),
+            password: "password".into(),
+            email: "Bob@Bücher.example".into(),
+            roles: vec![Role::User],
+            image: None,
+        }
+        .into();
+        assert_eq!(user.email, "Bob@Bücher.example");
+        assert_eq!(
+            user.email_normalized,
+            normalize_email("bob@XN--BCHER-KVA.EXAMPLE")
+        );
+    }
+}
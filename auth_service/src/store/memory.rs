@@ -0,0 +1,75 @@
+use super::{DuplicateKey, UserStore, UserUpdate};
+use crate::schema::UserWithHash;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Keeps users in memory, for tests and throwaway instances. Everything is lost on restart.
+#[derive(Default)]
+pub struct MemoryUserStore {
+    users: RwLock<HashMap<String, UserWithHash>>,
+}
+
+impl MemoryUserStore {
+    fn email_taken(users: &HashMap<String, UserWithHash>, user: &UserWithHash) -> bool {
+        users
+            .values()
+            .any(|u| u.id != user.id && u.email_normalized == user.email_normalized)
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn create_user(&self, user: &UserWithHash) -> Result<()> {
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(&user.id) || Self::email_taken(&users, user) {
+            return Err(DuplicateKey.into());
+        }
+        users.insert(user.id.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn get_user_from_id(&self, id: &str) -> Result<Option<UserWithHash>> {
+        Ok(self.users.read().unwrap().get(id).cloned())
+    }
+
+    async fn get_user_from_email(&self, email_normalized: &str) -> Result<Option<UserWithHash>> {
+        Ok(self
+            .users
+            .read()
+            .unwrap()
+            .values()
+            .find(|u| u.email_normalized == email_normalized)
+            .cloned())
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<UserWithHash>> {
+        Ok(self.users.read().unwrap().values().cloned().collect())
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<()> {
+        self.users.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn update_user_with(&self, id: &str, update: &UserUpdate<'_>) -> Result<bool> {
+        let mut users = self.users.write().unwrap();
+        let mut user = match users.get(id) {
+            Some(user) => user.clone(),
+            None => return Ok(false),
+        };
+        if !update(&mut user) {
+            return Ok(false);
+        }
+        if Self::email_taken(&users, &user) {
+            return Err(DuplicateKey.into());
+        }
+        users.insert(id.to_string(), user);
+        Ok(true)
+    }
+}
@@ -0,0 +1,235 @@
+use crate::config::{DefaultUser, UserStoreBackend};
+use crate::crypto;
+use crate::schema::{normalize_email, AccountStatus, Role, TwoFactor, User, UserWithHash};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use mongodb::Database;
+use std::fmt;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+mod memory;
+mod mongo;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use memory::MemoryUserStore;
+pub use mongo::MongoUserStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteUserStore;
+
+/// A write was rejected because the id or the address of the user is taken.
+#[derive(Debug)]
+pub struct DuplicateKey;
+
+impl fmt::Display for DuplicateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "duplicate key")
+    }
+}
+
+impl std::error::Error for DuplicateKey {}
+
+/// Whether a write was rejected by a unique constraint, of any backend.
+pub fn is_duplicate_key(err: &anyhow::Error) -> bool {
+    err.is::<DuplicateKey>() || crate::mongo::is_duplicate_key(err)
+}
+
+/// Creates the store selected with `USER_STORE`.
+pub fn from_config(backend: &UserStoreBackend, database: &Database) -> Result<Arc<dyn UserStore>> {
+    Ok(match backend {
+        UserStoreBackend::Mongo => Arc::new(MongoUserStore::new(database.collection("users"))),
+        UserStoreBackend::Memory => Arc::new(MemoryUserStore::default()),
+        #[cfg(feature = "sqlite")]
+        UserStoreBackend::Sqlite { path } => Arc::new(SqliteUserStore::open(path)?),
+        #[cfg(not(feature = "sqlite"))]
+        UserStoreBackend::Sqlite { path } => {
+            anyhow::bail!("can not open {}, sqlite needs the sqlite feature", path)
+        }
+    })
+}
+
+/// Checks the password of the default user, or creates it if `create` is set.
+/// Panics if it is missing otherwise.
+#[tracing::instrument(level = "trace", skip(users, default_user))]
+pub async fn ensure_default_user(users: &dyn UserStore, default_user: &DefaultUser) -> Result<()> {
+    match users
+        .get_user_from_email(&normalize_email(&default_user.name))
+        .await?
+    {
+        Some(user) => {
+            if !crypto::verify(user.hash.0, &default_user.pass) {
+                warn!("default user password changed from config");
+            }
+        }
+        None => {
+            if default_user.create {
+                warn!("No default user, creating according to config");
+                let user: UserWithHash = User {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    name: "".into(),
+                    password: default_user.pass.clone(),
+                    email: default_user.name.clone(),
+                    roles: vec![Role::Admin, Role::Moderator, Role::User],
+                    image: None,
+                }
+                .into();
+                users.create_user(&user).await?;
+                info!("adding user {:?} with roles {:?}", user.email, user.roles);
+            } else {
+                error!("No default user");
+                panic!("No default user");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Changes a user in place, returns false to leave it unchanged.
+pub type UserUpdate<'a> = dyn Fn(&mut UserWithHash) -> bool + Send + Sync + 'a;
+
+/// Storage of the user records. Ids and normalized addresses are unique.
+///
+/// Backends implement lookups and [`UserStore::update_user_with`],
+/// the single field updates are built on it unless the backend has a native way to do them.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Fails with [`DuplicateKey`] if the id or address is taken.
+    async fn create_user(&self, user: &UserWithHash) -> Result<()>;
+
+    async fn get_user_from_id(&self, id: &str) -> Result<Option<UserWithHash>>;
+
+    /// Looks a user up by an address as returned by [`normalize_email`].
+    async fn get_user_from_email(&self, email_normalized: &str) -> Result<Option<UserWithHash>>;
+
+    async fn get_all_users(&self) -> Result<Vec<UserWithHash>>;
+
+    /// Like [`UserStore::get_user_from_id`], but a missing user is an error.
+    async fn require_user(&self, id: &str) -> Result<UserWithHash> {
+        self.get_user_from_id(id)
+            .await?
+            .ok_or_else(|| anyhow!("User not found"))
+    }
+
+    /// Looks a user up by any spelling of the address, a missing user is an error.
+    async fn require_user_by_email(&self, email: &str) -> Result<UserWithHash> {
+        self.get_user_from_email(&normalize_email(email))
+            .await?
+            .ok_or_else(|| anyhow!("User not found"))
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<()>;
+
+    /// Checks that the storage answers, for the readiness probe.
+    async fn ping(&self) -> Result<()>;
+
+    /// Atomically applies `update` to the user.
+    /// Returns whether the user was changed, fails with [`DuplicateKey`] if the new address is taken.
+    async fn update_user_with(&self, id: &str, update: &UserUpdate<'_>) -> Result<bool>;
+
+    /// Changes the address of the user if it is still `from`.
+    async fn change_email(&self, id: &str, from: &str, to: &str) -> Result<bool> {
+        self.update_user_with(id, &|u| {
+            if u.email != from {
+                return false;
+            }
+            u.email = to.to_string();
+            u.email_normalized = normalize_email(to);
+            true
+        })
+        .await
+    }
+
+    async fn bump_token_version(&self, id: &str) -> Result<()> {
+        self.update_user_with(id, &|u| {
+            u.token_version += 1;
+            true
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn activate_user(&self, id: &str) -> Result<()> {
+        self.update_user_with(id, &|u| {
+            u.status = AccountStatus::Active;
+            true
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Records a new verification mail for a pending user,
+    /// returns false if the last one was sent less than `interval` seconds ago.
+    async fn claim_verification_mail(&self, id: &str, now: i64, interval: i64) -> Result<bool> {
+        self.update_user_with(id, &|u| {
+            let due = u
+                .verification_sent_at
+                .is_none_or(|sent| sent < now - interval);
+            if u.status != AccountStatus::Pending || !due {
+                return false;
+            }
+            u.verification_sent_at = Some(now);
+            true
+        })
+        .await
+    }
+
+    async fn set_two_factor(&self, id: &str, two_factor: Option<&TwoFactor>) -> Result<()> {
+        self.update_user_with(id, &|u| {
+            u.two_factor = two_factor.cloned();
+            true
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn confirm_two_factor(&self, id: &str, recovery_codes: &[String]) -> Result<()> {
+        self.update_user_with(id, &|u| match &mut u.two_factor {
+            Some(two_factor) => {
+                two_factor.confirmed = true;
+                two_factor.recovery_codes = recovery_codes.to_vec();
+                true
+            }
+            None => false,
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_recovery_codes(&self, id: &str, recovery_codes: &[String]) -> Result<()> {
+        self.update_user_with(id, &|u| match &mut u.two_factor {
+            Some(two_factor) => {
+                two_factor.recovery_codes = recovery_codes.to_vec();
+                true
+            }
+            None => false,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Marks a TOTP time step as used, returns false if it or a later one was used before.
+    async fn claim_totp_step(&self, id: &str, step: i64) -> Result<bool> {
+        self.update_user_with(id, &|u| match &mut u.two_factor {
+            Some(two_factor) if two_factor.last_step < step => {
+                two_factor.last_step = step;
+                true
+            }
+            _ => false,
+        })
+        .await
+    }
+
+    /// Removes a recovery code so it can only be used once.
+    async fn take_recovery_code(&self, id: &str, hash: &str) -> Result<bool> {
+        self.update_user_with(id, &|u| match &mut u.two_factor {
+            Some(two_factor) => {
+                let before = two_factor.recovery_codes.len();
+                two_factor.recovery_codes.retain(|code| code != hash);
+                two_factor.recovery_codes.len() != before
+            }
+            None => false,
+        })
+        .await
+    }
+}
@@ -0,0 +1,276 @@
+use super::{UserStore, UserUpdate};
+use crate::schema::{normalize_email, AccountStatus, TwoFactor, UserWithHash};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures_util::stream::StreamExt;
+use mongodb::bson::{self, doc, Document};
+use mongodb::Collection;
+use tracing::warn;
+
+/// Attempts of [`MongoUserStore::update_user_with`] before giving up on concurrent writes.
+const UPDATE_ATTEMPTS: usize = 5;
+
+/// Keeps users in the `users` collection, with native updates for the single field changes.
+pub struct MongoUserStore {
+    users: Collection<UserWithHash>,
+}
+
+impl MongoUserStore {
+    pub fn new(users: Collection<UserWithHash>) -> Self {
+        Self { users }
+    }
+}
+
+#[async_trait]
+impl UserStore for MongoUserStore {
+    async fn create_user(&self, user: &UserWithHash) -> Result<()> {
+        self.users.insert_one(user, None).await?;
+        Ok(())
+    }
+
+    async fn get_user_from_id(&self, id: &str) -> Result<Option<UserWithHash>> {
+        self.users
+            .find_one(doc! {"id": id}, None)
+            .await
+            .map_err(|e| {
+                warn!("Error while searching for user: {:?}", e);
+                e.into()
+            })
+    }
+
+    async fn get_user_from_email(&self, email_normalized: &str) -> Result<Option<UserWithHash>> {
+        self.users
+            .find_one(doc! {"email_normalized": email_normalized}, None)
+            .await
+            .map_err(|e| {
+                warn!("Error while searching for user: {:?}", e);
+                e.into()
+            })
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<UserWithHash>> {
+        match self.users.find(doc! {}, None).await {
+            Ok(cursor) => Ok(cursor.filter_map(|v| async { v.ok() }).collect().await),
+            Err(e) => {
+                warn!("error while listing all users: {:?}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<()> {
+        self.users.delete_one(doc! {"id": id}, None).await?;
+        Ok(())
+    }
+
+    /// Reads the collection metadata, the users themselves are not scanned.
+    async fn ping(&self) -> Result<()> {
+        self.users.estimated_document_count(None).await?;
+        Ok(())
+    }
+
+    /// Sets only the fields changed by `update`, on the condition that they still hold the values
+    /// that were read. If another write changed them in between the update is applied again,
+    /// writes to other fields, like [`UserStore::bump_token_version`], are kept.
+    async fn update_user_with(&self, id: &str, update: &UserUpdate<'_>) -> Result<bool> {
+        let documents = self.users.clone_with_type::<Document>();
+        for _ in 0..UPDATE_ATTEMPTS {
+            let stored = match documents.find_one(doc! {"id": id}, None).await? {
+                Some(stored) => stored,
+                None => return Ok(false),
+            };
+            let mut user: UserWithHash = bson::from_document(stored.clone())?;
+            if !update(&mut user) {
+                return Ok(false);
+            }
+            let (filter, set) = match guarded_set(id, &stored, bson::to_document(&user)?) {
+                Some(update) => update,
+                None => return Ok(true),
+            };
+            let result = documents
+                .update_one(filter, doc! {"$set": set}, None)
+                .await?;
+            if result.matched_count == 1 {
+                return Ok(true);
+            }
+        }
+        bail!(
+            "user {} was changed concurrently {} times",
+            id,
+            UPDATE_ATTEMPTS
+        )
+    }
+
+    async fn change_email(&self, id: &str, from: &str, to: &str) -> Result<bool> {
+        let result = self
+            .users
+            .update_one(
+                doc! {"id": id, "email": from},
+                doc! {"$set": {"email": to, "email_normalized": normalize_email(to)}},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn bump_token_version(&self, id: &str) -> Result<()> {
+        self.users
+            .update_one(doc! {"id": id}, doc! {"$inc": {"token_version": 1}}, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn activate_user(&self, id: &str) -> Result<()> {
+        self.users
+            .update_one(
+                doc! {"id": id},
+                doc! {"$set": {"status": mongodb::bson::to_bson(&AccountStatus::Active)?}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_verification_mail(&self, id: &str, now: i64, interval: i64) -> Result<bool> {
+        let result = self
+            .users
+            .update_one(
+                doc! {
+                    "id": id,
+                    "status": mongodb::bson::to_bson(&AccountStatus::Pending)?,
+                    "$or": [
+                        {"verification_sent_at": null},
+                        {"verification_sent_at": {"$lt": now - interval}},
+                    ],
+                },
+                doc! {"$set": {"verification_sent_at": now}},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn set_two_factor(&self, id: &str, two_factor: Option<&TwoFactor>) -> Result<()> {
+        self.users
+            .update_one(
+                doc! {"id": id},
+                doc! {"$set": {"two_factor": mongodb::bson::to_bson(&two_factor)?}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn confirm_two_factor(&self, id: &str, recovery_codes: &[String]) -> Result<()> {
+        self.users
+            .update_one(
+                doc! {"id": id},
+                doc! {"$set": {
+                    "two_factor.confirmed": true,
+                    "two_factor.recovery_codes": recovery_codes,
+                }},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_recovery_codes(&self, id: &str, recovery_codes: &[String]) -> Result<()> {
+        self.users
+            .update_one(
+                doc! {"id": id},
+                doc! {"$set": {"two_factor.recovery_codes": recovery_codes}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_totp_step(&self, id: &str, step: i64) -> Result<bool> {
+        let result = self
+            .users
+            .update_one(
+                doc! {"id": id, "two_factor.last_step": {"$lt": step}},
+                doc! {"$set": {"two_factor.last_step": step}},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn take_recovery_code(&self, id: &str, hash: &str) -> Result<bool> {
+        let result = self
+            .users
+            .update_one(
+                doc! {"id": id, "two_factor.recovery_codes": hash},
+                doc! {"$pull": {"two_factor.recovery_codes": hash}},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+}
+
+/// Filter and `$set` of the fields of `updated` that differ from `stored`. The filter matches
+/// only while those fields hold their `stored` values, absent fields have to be absent still.
+/// Returns `None` if nothing changed.
+fn guarded_set(id: &str, stored: &Document, updated: Document) -> Option<(Document, Document)> {
+    let mut filter = doc! {"id": id};
+    let mut set = Document::new();
+    for (field, value) in updated {
+        match stored.get(&field) {
+            Some(old) if *old == value => continue,
+            Some(old) => filter.insert(field.clone(), old.clone()),
+            None => filter.insert(field.clone(), doc! {"$exists": false}),
+        };
+        set.insert(field, value);
+    }
+    if set.is_empty() {
+        None
+    } else {
+        Some((filter, set))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_only_changed_fields_guarded_by_their_old_values() {
+        let stored =
+            doc! {"_id": 1, "id": "u", "name": "old", "token_version": 3, "roles": ["User"]};
+        let updated = doc! {"id": "u", "name": "new", "token_version": 3, "roles": ["User"]};
+
+        let (filter, set) = guarded_set("u", &stored, updated).unwrap();
+        assert_eq!(filter, doc! {"id": "u", "name": "old"});
+        assert_eq!(set, doc! {"name": "new"});
+    }
+
+    #[test]
+    fn a_concurrent_token_version_bump_breaks_the_guard() {
+        let stored = doc! {"id": "u", "hash": "old", "token_version": 3};
+        let updated = doc! {"id": "u", "hash": "new", "token_version": 4};
+
+        let (filter, set) = guarded_set("u", &stored, updated).unwrap();
+        // a bump in between leaves token_version at 4 and the filter without a match
+        assert_eq!(filter, doc! {"id": "u", "hash": "old", "token_version": 3});
+        assert_eq!(set, doc! {"hash": "new", "token_version": 4});
+    }
+
+    #[test]
+    fn fields_missing_from_the_document_must_stay_missing() {
+        let stored = doc! {"id": "u"};
+        let updated = doc! {"id": "u", "two_factor": {"confirmed": false}};
+
+        let (filter, set) = guarded_set("u", &stored, updated).unwrap();
+        assert_eq!(filter, doc! {"id": "u", "two_factor": {"$exists": false}});
+        assert_eq!(set, doc! {"two_factor": {"confirmed": false}});
+    }
+
+    #[test]
+    fn nothing_changed_needs_no_write() {
+        let stored = doc! {"id": "u", "name": "same"};
+        assert!(guarded_set("u", &stored, stored.clone()).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+use super::{DuplicateKey, UserStore, UserUpdate};
+use crate::schema::UserWithHash;
+use actix_web::web;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use std::sync::{Arc, Mutex};
+
+/// Attempts of [`SqliteUserStore::update_user_with`] before giving up on concurrent writes.
+const UPDATE_ATTEMPTS: usize = 5;
+
+/// Keeps users in a SQLite file, for single node installs.
+/// Users are stored as JSON next to the columns needed for lookups and uniqueness.
+/// SQLite blocks the calling thread, so queries run on the blocking pool.
+pub struct SqliteUserStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteUserStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                id TEXT PRIMARY KEY,
+                email_normalized TEXT NOT NULL UNIQUE,
+                data TEXT NOT NULL
+            )",
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn run<T, F>(&self, query: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        web::block(move || query(&connection.lock().unwrap())).await?
+    }
+
+    /// Returns the stored JSON, [`SqliteUserStore::update_user_with`] compares it before writing.
+    async fn find_data(&self, column: &'static str, value: &str) -> Result<Option<String>> {
+        let value = value.to_string();
+        self.run(move |connection| {
+            Ok(connection
+                .query_row(
+                    &format!("SELECT data FROM users WHERE {} = ?1", column),
+                    params![value],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn find(&self, column: &'static str, value: &str) -> Result<Option<UserWithHash>> {
+        self.find_data(column, value)
+            .await?
+            .map(|data| serde_json::from_str(&data).map_err(Into::into))
+            .transpose()
+    }
+}
+
+/// Maps violated unique constraints to [`DuplicateKey`].
+fn write_error(err: rusqlite::Error) -> anyhow::Error {
+    match err {
+        rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation => {
+            DuplicateKey.into()
+        }
+        err => err.into(),
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteUserStore {
+    async fn create_user(&self, user: &UserWithHash) -> Result<()> {
+        let (id, email_normalized) = (user.id.clone(), user.email_normalized.clone());
+        let data = serde_json::to_string(user)?;
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO users (id, email_normalized, data) VALUES (?1, ?2, ?3)",
+                    params![id, email_normalized, data],
+                )
+                .map_err(write_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_user_from_id(&self, id: &str) -> Result<Option<UserWithHash>> {
+        self.find("id", id).await
+    }
+
+    async fn get_user_from_email(&self, email_normalized: &str) -> Result<Option<UserWithHash>> {
+        self.find("email_normalized", email_normalized).await
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<UserWithHash>> {
+        self.run(|connection| {
+            let mut statement = connection.prepare("SELECT data FROM users")?;
+            let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+            rows.map(|data| Ok(serde_json::from_str(&data?)?)).collect()
+        })
+        .await
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<()> {
+        let id = id.to_string();
+        self.run(move |connection| {
+            connection.execute("DELETE FROM users WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.run(|connection| Ok(connection.query_row("SELECT 1", [], |_| Ok(()))?))
+            .await
+    }
+
+    /// The update runs outside the connection, the write only applies if the row is unchanged.
+    async fn update_user_with(&self, id: &str, update: &UserUpdate<'_>) -> Result<bool> {
+        for _ in 0..UPDATE_ATTEMPTS {
+            let stored = match self.find_data("id", id).await? {
+                Some(stored) => stored,
+                None => return Ok(false),
+            };
+            let mut user: UserWithHash = serde_json::from_str(&stored)?;
+            if !update(&mut user) {
+                return Ok(false);
+            }
+            let data = serde_json::to_string(&user)?;
+            if data == stored {
+                return Ok(true);
+            }
+
+            let (id, email_normalized) = (id.to_string(), user.email_normalized);
+            let changed = self
+                .run(move |connection| {
+                    connection
+                        .execute(
+                            "UPDATE users SET email_normalized = ?2, data = ?3
+                             WHERE id = ?1 AND data = ?4",
+                            params![id, email_normalized, data, stored],
+                        )
+                        .map_err(write_error)
+                })
+                .await?;
+            if changed == 1 {
+                return Ok(true);
+            }
+        }
+        bail!(
+            "user {} was changed concurrently {} times",
+            id,
+            UPDATE_ATTEMPTS
+        )
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::schema::normalize_email;
+    use crate::store::is_duplicate_key;
+    use crate::testing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn store() -> SqliteUserStore {
+        SqliteUserStore::open(":memory:").unwrap()
+    }
+
+    #[actix_web::test]
+    async fn finds_users_by_id_and_normalized_email() {
+        let store = store();
+        let user = testing::user("Alice@Example.com", "password");
+        store.create_user(&user).await.unwrap();
+
+        let found = store
+            .get_user_from_email(&normalize_email("alice@EXAMPLE.com"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.id, user.id);
+        assert_eq!(found.email, "Alice@Example.com");
+        assert!(store.get_user_from_id(&user.id).await.unwrap().is_some());
+        assert!(store
+            .get_user_from_email("bob@example.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn taken_addresses_are_duplicate_keys() {
+        let store = store();
+        store
+            .create_user(&testing::user("alice@example.com", "password"))
+            .await
+            .unwrap();
+
+        let err = store
+            .create_user(&testing::user("ALICE@example.com", "password"))
+            .await
+            .unwrap_err();
+        assert!(is_duplicate_key(&err));
+        assert_eq!(store.get_all_users().await.unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn updates_to_a_taken_address_are_duplicate_keys() {
+        let store = store();
+        let alice = testing::user("alice@example.com", "password");
+        store.create_user(&alice).await.unwrap();
+        store
+            .create_user(&testing::user("bob@example.com", "password"))
+            .await
+            .unwrap();
+
+        let err = store
+            .update_user_with(&alice.id, &|user| {
+                user.email_normalized = normalize_email("bob@example.com");
+                true
+            })
+            .await
+            .unwrap_err();
+        assert!(is_duplicate_key(&err));
+        let stored = store.get_user_from_id(&alice.id).await.unwrap().unwrap();
+        assert_eq!(stored.email_normalized, alice.email_normalized);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_writes_are_not_overwritten() {
+        let store = store();
+        let user = testing::user("alice@example.com", "password");
+        store.create_user(&user).await.unwrap();
+
+        let connection = store.connection.clone();
+        let calls = AtomicUsize::new(0);
+        let updated = store
+            .update_user_with(&user.id, &|stored| {
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    // another writer bumps the token version after the first read
+                    let mut bumped = stored.clone();
+                    bumped.token_version += 1;
+                    connection
+                        .lock()
+                        .unwrap()
+                        .execute(
+                            "UPDATE users SET data = ?2 WHERE id = ?1",
+                            params![bumped.id, serde_json::to_string(&bumped).unwrap()],
+                        )
+                        .unwrap();
+                }
+                stored.name = "Alice".to_string();
+                true
+            })
+            .await
+            .unwrap();
+
+        assert!(updated);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let stored = store.get_user_from_id(&user.id).await.unwrap().unwrap();
+        assert_eq!(stored.token_version, user.token_version + 1);
+        assert_eq!(stored.name, "Alice");
+    }
+
+    #[actix_web::test]
+    async fn updates_of_missing_users_report_false() {
+        let store = store();
+        assert!(!store.update_user_with("missing", &|_| true).await.unwrap());
+    }
+}
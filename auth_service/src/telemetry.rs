@@ -0,0 +1,87 @@
+use crate::config::{LogFormat, TraceExporter, TracingConfig};
+use anyhow::Result;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::sdk::propagation::{TextMapCompositePropagator, TraceContextPropagator};
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{global, runtime, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// Installs the log written to stdout and, unless the exporter is `none`, the span exporter.
+/// The filter only applies to the log, exported spans are sampled instead.
+pub fn init(config: &TracingConfig) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.log_filter)?;
+    let stdout = match config.log_format {
+        LogFormat::Human => fmt::layer().with_filter(filter).boxed(),
+        LogFormat::Json => fmt::layer().json().with_filter(filter).boxed(),
+    };
+    let opentelemetry =
+        tracer(config)?.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    tracing_subscriber::registry()
+        .with(stdout)
+        .with(opentelemetry)
+        .try_init()?;
+    Ok(())
+}
+
+fn tracer(config: &TracingConfig) -> Result<Option<Tracer>> {
+    if let TraceExporter::None = config.exporter {
+        return Ok(None);
+    }
+
+    // the other services propagate jaeger headers, accept and send both formats
+    let propagators: Vec<Box<dyn TextMapPropagator + Send + Sync>> = vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(opentelemetry_jaeger::Propagator::new()),
+    ];
+    global::set_text_map_propagator(TextMapCompositePropagator::new(propagators));
+
+    let trace_config = trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]));
+
+    let tracer = match config.exporter {
+        TraceExporter::None => unreachable!(),
+        TraceExporter::Jaeger => {
+            let mut pipeline = opentelemetry_jaeger::new_pipeline()
+                .with_service_name(&config.service_name)
+                .with_trace_config(trace_config);
+            if let Some(endpoint) = &config.endpoint {
+                pipeline = pipeline.with_agent_endpoint(endpoint);
+            }
+            pipeline.install_batch(runtime::Tokio)?
+        }
+        TraceExporter::OtlpGrpc => {
+            let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+            if let Some(endpoint) = &config.endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(trace_config)
+                .install_batch(runtime::Tokio)?
+        }
+        TraceExporter::OtlpHttp => {
+            let mut exporter = opentelemetry_otlp::new_exporter().http();
+            if let Some(endpoint) = &config.endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(trace_config)
+                .install_batch(runtime::Tokio)?
+        }
+    };
+    Ok(Some(tracer))
+}
@@ -0,0 +1,176 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::Config;
+use crate::crypto::webauthn::{ES256, FLAG_ATTESTED_CREDENTIAL, FLAG_USER_PRESENT};
+use crate::crypto::JwtIssuer;
+use crate::mail::Mailer;
+use crate::mongo::Mongo;
+use crate::schema::{Role, User, UserWithHash};
+use crate::store::{MemoryUserStore, UserStore};
+use ciborium::value::Value;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use sodiumoxide::crypto::hash::sha256;
+use std::env;
+use std::sync::Arc;
+
+/// Config with an hmac secret and the memory user store. Neither the database nor
+/// the mail server are reachable.
+pub fn config() -> Config {
+    Config::from_toml(
+        r#"
+        public_url = "https://board.test"
+        user_store = "memory"
+
+        [image_service]
+        url = "localhost:1"
+
+        [db]
+        address = "127.0.0.1"
+        port = "1"
+        user = "test"
+        password = "test"
+
+        [smtp]
+        host = "127.0.0.1"
+        port = 1
+
+        [default_user]
+        pass = "default password"
+
+        [jwt]
+        secret = "test secret"
+
+        [webauthn]
+        rp_id = "board.test"
+        origin = "https://board.test"
+        "#,
+    )
+    .unwrap()
+}
+
+pub async fn jwt_issuer(config: &Config) -> Arc<JwtIssuer> {
+    Arc::new(JwtIssuer::new(config.clone()).await.unwrap())
+}
+
+/// Mongo whose server does not exist, for paths that must not touch it.
+pub async fn mongo(config: &Config) -> Arc<Mongo> {
+    Arc::new(Mongo::open(config).await.unwrap())
+}
+
+/// Mongo at `TEST_DB_ADDRESS` (default localhost) with the credentials of the compose file.
+/// Only for the tests marked `#[ignore]`, run them with `cargo test -- --ignored`.
+pub async fn live_mongo(config: &Config) -> Arc<Mongo> {
+    let mut config = config.clone();
+    config.db.address = env::var("TEST_DB_ADDRESS").unwrap_or_else(|_| "localhost".into());
+    config.db.port = "27017".into();
+    config.db.user = "admin".into();
+    config.db.password = "admin".into();
+    let mongo = Mongo::open(&config).await.unwrap();
+    mongo.connect().await.unwrap();
+    Arc::new(mongo)
+}
+
+/// A user store without mongo, for handlers that only need the users.
+pub fn users() -> Arc<dyn UserStore> {
+    Arc::new(MemoryUserStore::default())
+}
+
+pub fn mailer(config: &Config) -> Mailer {
+    Mailer::new(config.smtp.clone()).unwrap()
+}
+
+pub fn user(email: &str, password: &str) -> UserWithHash {
+    User {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: email.split('@').next().unwrap().to_string(),
+        password: password.to_string(),
+        email: email.to_string(),
+        roles: vec![Role::User],
+        image: None,
+    }
+    .into()
+}
+
+/// Authenticator signing with a P-256 key held in memory.
+pub struct SoftAuthenticator {
+    rng: SystemRandom,
+    key_pair: EcdsaKeyPair,
+    pub credential_id: Vec<u8>,
+    rp_id: String,
+}
+
+impl SoftAuthenticator {
+    pub fn new(rp_id: &str) -> Self {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        Self {
+            key_pair: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+                .unwrap(),
+            rng,
+            // unique, credential ids are unique in mongo
+            credential_id: uuid::Uuid::new_v4().as_bytes().to_vec(),
+            rp_id: rp_id.to_string(),
+        }
+    }
+
+    /// Uncompressed P-256 point, as stored for the credential.
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    pub fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::json!({"type": kind, "challenge": challenge, "origin": origin})
+            .to_string()
+            .into_bytes()
+    }
+
+    fn authenticator_data(&self, flags: u8, sign_count: u32) -> Vec<u8> {
+        let mut data = sha256::hash(self.rp_id.as_bytes()).0.to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        data
+    }
+
+    /// Attestation object with `none` attestation, as returned by `navigator.credentials.create()`.
+    pub fn attestation_object(&self, sign_count: u32) -> Vec<u8> {
+        let point = self.key_pair.public_key().as_ref();
+        let key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(2.into())),
+            (Value::Integer(3.into()), Value::Integer(ES256.into())),
+            (Value::Integer((-1).into()), Value::Integer(1.into())),
+            (
+                Value::Integer((-2).into()),
+                Value::Bytes(point[1..33].to_vec()),
+            ),
+            (
+                Value::Integer((-3).into()),
+                Value::Bytes(point[33..].to_vec()),
+            ),
+        ]);
+        let mut auth_data =
+            self.authenticator_data(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL, sign_count);
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        ciborium::ser::into_writer(&key, &mut auth_data).unwrap();
+
+        let object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+            (Value::Text("authData".into()), Value::Bytes(auth_data)),
+        ]);
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&object, &mut bytes).unwrap();
+        bytes
+    }
+
+    /// Authenticator data and signature, as returned by `navigator.credentials.get()`.
+    pub fn assert(&self, client_data_json: &[u8], sign_count: u32) -> (Vec<u8>, Vec<u8>) {
+        let auth_data = self.authenticator_data(FLAG_USER_PRESENT, sign_count);
+        let mut message = auth_data.clone();
+        message.extend_from_slice(&sha256::hash(client_data_json).0);
+        let signature = self.key_pair.sign(&self.rng, &message).unwrap();
+        (auth_data, signature.as_ref().to_vec())
+    }
+}
@@ -0,0 +1,73 @@
+use crate::config::TlsConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+
+/// Hands out the configured certificate to new connections.
+/// [`CertResolver::reload`] picks up renewed files without a restart, open connections keep the old one.
+pub struct CertResolver {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(config: TlsConfig) -> Result<Self> {
+        let current = RwLock::new(Arc::new(load(&config)?));
+        Ok(Self { config, current })
+    }
+
+    /// Reads the files again, the current certificate stays in use if they are invalid.
+    pub fn reload(&self) -> Result<()> {
+        let key = load(&self.config)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    pub fn server_config(self: Arc<Self>) -> rustls::ServerConfig {
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load(config: &TlsConfig) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+        .with_context(|| format!("can not parse {}", config.cert_path))?;
+    if certs.is_empty() {
+        bail!("no certificate found in {}", config.cert_path);
+    }
+
+    let key = rustls_pemfile::read_all(&mut open(&config.key_path)?)
+        .with_context(|| format!("can not parse {}", config.key_path))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key found in {}", config.key_path))?;
+    let key = any_supported_type(&PrivateKey(key))
+        .map_err(|_| anyhow!("unsupported private key in {}", config.key_path))?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    Ok(BufReader::new(
+        File::open(path).with_context(|| format!("can not read {}", path))?,
+    ))
+}